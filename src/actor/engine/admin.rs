@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::archive::Archive;
+use super::backup::{create_empty_dir, write_synced, Manifest, MANIFEST_FILE};
+use super::chunk::{LogChunk, MAX_CHUNK_SIZE};
+use super::chunk_files::{self, ChunkFiles};
+use super::storage::FileStorage;
+use super::event::COMMIT_EVENT_TYPE;
+use super::index::Index;
+use super::keyring::Keyring;
+
+// Checks and repairs of a data directory done while the server isn't running, the directory is
+// the one the server is started in, holding the chunks directory.  Given the server's archive,
+// the chunks moved there are checked along with the others.
+
+// Where corrupt chunks are moved to, outside the chunks directory so the server doesn't find them.
+const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug)]
+pub enum ChunkProblem {
+  // The header can't be read or its hash matches none of the record boundaries.
+  Corrupt(String),
+  // The chunk doesn't chain on to the chunk before it.
+  BrokenChain,
+  // Bytes after the last record boundary the hash matches, left by a write that never finished.
+  TornTail(usize),
+  // No file for a chunk between the first and the last, such as one that was quarantined.
+  Missing
+}
+
+#[derive(Debug)]
+pub struct ChunkReport {
+  pub chunk_number : u32,
+  pub version      : u8,
+  pub key_id       : u32,
+  // Events and streams are only counted when the chunk could be decoded.
+  pub events       : Option<usize>,
+  pub streams      : Option<usize>,
+  pub bytes_used   : usize,
+  pub bytes_free   : usize,
+  // Whether the chunk is only in the archive, where it can't be repaired or quarantined.
+  pub archived     : bool,
+  pub problems     : Vec<ChunkProblem>
+}
+
+impl ChunkReport {
+  // Report on a chunk nothing could be read from.
+  fn empty(chunk_number: u32, problem: ChunkProblem) -> Self {
+    Self {
+      chunk_number,
+      version    : 0,
+      key_id     : 0,
+      events     : None,
+      streams    : None,
+      bytes_used : 0,
+      bytes_free : 0,
+      archived   : false,
+      problems   : vec![problem]
+    }
+  }
+
+  pub fn is_corrupt(&self) -> bool {
+    self.problems.iter().any(|problem| matches!(problem, ChunkProblem::Corrupt(_)))
+  }
+}
+
+// Where the chunks checked or restored are read from.
+enum ChunkSource {
+  // A data directory's chunks, along with those moved to its archive, and the directory archived
+  // chunks are fetched into if there is one.
+  Store(ChunkFiles, Option<String>),
+  // Files holding copies of chunks, such as those in backups, by chunk number.
+  Files(BTreeMap<u32, String>)
+}
+
+impl ChunkSource {
+  fn chunk_ids(&self) -> Result<Vec<u32>, String> {
+    match self {
+      ChunkSource::Store(chunk_files, _) => chunk_files.chunk_ids(),
+      ChunkSource::Files(files) => Ok(files.keys().cloned().collect())
+    }
+  }
+
+  fn contents(&self, chunk_number: u32) -> Result<Vec<u8>, String> {
+    match self {
+      ChunkSource::Store(chunk_files, _) => chunk_files.contents(chunk_number),
+      ChunkSource::Files(files) => match files.get(&chunk_number) {
+        Some(path) => fs::read(path).map_err(|error| format!("{}: {}", path, error)),
+        None => Err(format!("No file for chunk {}", chunk_number))
+      }
+    }
+  }
+
+  fn is_archived(&self, chunk_number: u32) -> bool {
+    match self {
+      ChunkSource::Store(chunk_files, _) => !chunk_files.is_local(chunk_number),
+      ChunkSource::Files(_) => false
+    }
+  }
+}
+
+// Only the cache this created is removed, never the one of a server using the same data directory.
+impl Drop for ChunkSource {
+  fn drop(&mut self) {
+    if let ChunkSource::Store(_, Some(cache_dir)) = self {
+      fs::remove_dir_all(cache_dir).ok();
+    }
+  }
+}
+
+// The chunks of data_dir, with the archive the server moves them to if it has one.  Archived chunks
+// are fetched into a temporary directory of their own, which is removed once done, while the
+// summaries in the data directory's chunk index are used like the server does.
+fn store_chunks(data_dir: &str, archive: Option<Arc<dyn Archive>>) -> Result<ChunkSource, String> {
+  let chunks_dir = format!("{}/chunks", data_dir);
+  if !Path::new(&chunks_dir).is_dir() {
+    return Err(format!("{} is not a directory", chunks_dir));
+  }
+
+  let storage = Arc::new(FileStorage::new(&chunks_dir)?);
+  let archive = match archive {
+    Some(archive) => archive,
+    None => return Ok(ChunkSource::Store(ChunkFiles::new(storage), None))
+  };
+
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|error| error.to_string())?.as_nanos();
+  let cache_dir = std::env::temp_dir().join(format!("betterstore-chunk-cache-{}-{}", std::process::id(), nanos));
+  let cache_dir = cache_dir.to_str().ok_or("The temporary directory isn't valid UTF-8")?.to_string();
+  let index_dir = format!("{}/{}", data_dir, chunk_files::INDEX_DIR);
+  match ChunkFiles::with_archive(storage, archive, &cache_dir, &index_dir, 1) {
+    Ok(chunk_files) => Ok(ChunkSource::Store(chunk_files, Some(cache_dir))),
+    Err(error) => {
+      fs::remove_dir_all(&cache_dir).ok();
+      Err(error)
+    }
+  }
+}
+
+// Reports on every chunk in order.  Without a key file encrypted chunks are still checked, but
+// their events aren't counted.
+pub fn check_chunks(data_dir: &str, key_file: Option<&str>, archive: Option<Arc<dyn Archive>>) -> Result<Vec<ChunkReport>, String> {
+  check_chunk_source(&store_chunks(data_dir, archive)?, &load_keyring(key_file)?)
+}
+
+// Checks the chunks as one store, in order of their numbers, wherever they are.
+fn check_chunk_source(source: &ChunkSource, keyring: &Keyring) -> Result<Vec<ChunkReport>, String> {
+  let mut reports   = Vec::new();
+  let mut previous_hash = Some([0; 32]);
+  for chunk_number in source.chunk_ids()? {
+    let archived = source.is_archived(chunk_number);
+    for missing in reports.len() as u32 + 1 .. chunk_number {
+      reports.push(ChunkReport::empty(missing, ChunkProblem::Missing));
+      previous_hash = None;
+    }
+
+    let scan = match source.contents(chunk_number).and_then(|contents| LogChunk::scan(&contents, keyring)) {
+      Ok(scan) => scan,
+      Err(error) => {
+        reports.push(ChunkReport { archived, ..ChunkReport::empty(chunk_number, ChunkProblem::Corrupt(error)) });
+        previous_hash = None;
+        continue;
+      }
+    };
+
+    let mut problems = Vec::new();
+    match scan.hashed_len {
+      Some(hashed_len) if hashed_len < scan.file_len => problems.push(ChunkProblem::TornTail(scan.file_len - hashed_len)),
+      Some(_) => {}
+      None => problems.push(ChunkProblem::Corrupt("Contents do not match the chunk's hash".to_string()))
+    }
+    // Nothing can be said about the chain right after a corrupt chunk, its hash can't be trusted.
+    if scan.version >= 6 && previous_hash.is_some_and(|previous_hash| previous_hash != scan.previous_hash) {
+      problems.push(ChunkProblem::BrokenChain);
+    }
+    previous_hash = scan.hashed_len.map(|_| scan.hash);
+
+    let events = scan.events.map(|events| {
+      events.into_iter().filter(|event| event.event_type != COMMIT_EVENT_TYPE).collect::<Vec<_>>()
+    });
+    let bytes_used = scan.hashed_len.unwrap_or(scan.file_len);
+
+    reports.push(ChunkReport {
+      chunk_number,
+      version    : scan.version,
+      key_id     : scan.key_id,
+      streams    : events.as_ref().map(|events| events.iter().map(|event| &event.name).collect::<HashSet<_>>().len()),
+      events     : events.map(|events| events.len()),
+      bytes_used,
+      bytes_free : (MAX_CHUNK_SIZE as usize).saturating_sub(bytes_used),
+      archived,
+      problems
+    });
+  }
+
+  Ok(reports)
+}
+
+// Cuts a torn write off the end of the chunk, as the server would when starting.
+pub fn repair_tail(data_dir: &str, report: &ChunkReport) -> Result<(), String> {
+  let file = OpenOptions::new()
+    .write(true)
+    .open(chunk_path(data_dir, report.chunk_number))
+    .map_err(|error| error.to_string())?;
+
+  file.set_len(report.bytes_used as u64).map_err(|error| error.to_string())?;
+  file.sync_all().map_err(|error| error.to_string())
+}
+
+// Moves the chunk out of the chunks directory so the server can start without it, returning where
+// it was moved to.  Its events are lost to the server, and later chunks no longer chain on to the
+// chunk before them.
+pub fn quarantine(data_dir: &str, chunk_number: u32) -> Result<String, String> {
+  let quarantine_dir = format!("{}/{}", data_dir, QUARANTINE_DIR);
+  fs::create_dir_all(&quarantine_dir).map_err(|error| error.to_string())?;
+
+  let destination = format!("{}/{}.chk", quarantine_dir, chunk_number);
+  fs::rename(chunk_path(data_dir, chunk_number), &destination).map_err(|error| error.to_string())?;
+  Ok(destination)
+}
+
+// Point a store is restored as of, every append up to and including it is kept.
+#[derive(Debug, Clone, Copy)]
+pub enum RestorePoint {
+  EventId(u64),
+  // Unix timestamp in seconds.
+  Timestamp(i64)
+}
+
+impl RestorePoint {
+  // Whether an append whose last event has the id and whose events were written at the latest
+  // at timestamp comes after the point.
+  fn is_before(&self, id: u64, timestamp: i64) -> bool {
+    match self {
+      RestorePoint::EventId(until) => id > *until,
+      RestorePoint::Timestamp(until) => timestamp > *until
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct RestoreSummary {
+  pub chunks  : usize,
+  pub streams : usize,
+  // Id the restored store's next event gets, one past the last event restored.
+  pub next_id : u64
+}
+
+// Restores backups taken with the Backup RPC into data_dir, which must not have any chunks yet.
+// backup_dirs are a full backup followed by the incremental backups taken on top of it, in the
+// order they were taken, and the store is restored as of the last of them.  The backups are
+// checked first and nothing is copied if any of it is damaged.  Stream keys go to
+// stream_keys_dir, the directory the restored server is to be started with.
+// With until the store is restored as of that point, leaving out every append after it.  A single
+// directory without a manifest is taken to be the data directory of a stopped server, to restore
+// it as of a point from before something went wrong, with archive being where that server moved
+// its full chunks to.  The restored store has every chunk on local disk.
+pub fn restore(backup_dirs: &[&str], data_dir: &str, key_file: Option<&str>, stream_keys_dir: &str, until: Option<RestorePoint>, archive: Option<Arc<dyn Archive>>) -> Result<RestoreSummary, String> {
+  let keyring = load_keyring(key_file)?;
+  let source  = restore_sources(backup_dirs, archive)?;
+
+  let reports = check_chunk_source(&source, &keyring)?;
+  if let Some(report) = reports.iter().find(|report| !report.problems.is_empty()) {
+    return Err(format!("Chunk {} of the backup is damaged: {:?}", report.chunk_number, report.problems));
+  }
+  // The index can't be rebuilt without decrypting the chunks.
+  if let Some(report) = reports.iter().find(|report| !keyring.has_key(report.key_id)) {
+    return Err(format!("Chunk {} is encrypted with key {}, which is not in the key file", report.chunk_number, report.key_id));
+  }
+
+  // Where the last append to keep ends, as the chunk number and length to cut that chunk off at.
+  let cut = match until {
+    Some(until) => Some(find_cut(&source, until, &keyring)?),
+    None => None
+  };
+
+  let chunks_dir = format!("{}/chunks", data_dir);
+  create_empty_dir(&chunks_dir)?;
+  let mut chunks = 0;
+  for chunk_number in source.chunk_ids()? {
+    let mut contents = source.contents(chunk_number)?;
+    match cut {
+      Some((cut_chunk, _)) if chunk_number > cut_chunk => break,
+      Some((cut_chunk, length)) if chunk_number == cut_chunk => LogChunk::truncate(&mut contents, length)?,
+      _ => {}
+    }
+
+    write_synced(&chunk_path(data_dir, chunk_number), &contents)?;
+    chunks += 1;
+  }
+
+  // Keys are taken from the last backup only, which has every key there was when it was taken.
+  let backup_keys_dir = format!("{}/stream_keys", backup_dirs.last().unwrap());
+  if let Ok(entries) = fs::read_dir(&backup_keys_dir) {
+    create_empty_dir(stream_keys_dir)?;
+    for entry in entries {
+      let entry    = entry.map_err(|error| error.to_string())?;
+      let contents = fs::read(entry.path()).map_err(|error| error.to_string())?;
+      write_synced(&format!("{}/{}", stream_keys_dir, entry.file_name().to_string_lossy()), &contents)?;
+    }
+  }
+
+  // The index is rebuilt the way the server does when starting, showing it can start from the backup.
+  let mut index = Index::new();
+  let (_, next_id) = index.initialize(&ChunkFiles::new(Arc::new(FileStorage::new(&chunks_dir)?)), &keyring);
+
+  Ok(RestoreSummary {
+    chunks,
+    streams : index.stream_names("", "").filter(|name| !name.starts_with('$')).count(),
+    next_id
+  })
+}
+
+// Finds the end of the last append at or before until.  Event ids only go up through the log, so
+// everything before that end is kept and nothing after it.
+fn find_cut(source: &ChunkSource, until: RestorePoint, keyring: &Keyring) -> Result<(u32, usize), String> {
+  let mut cut = None;
+  // Last id and latest timestamp of the append whose commit record hasn't been reached yet.
+  let mut pending : Option<(u64, i64)> = None;
+
+  for chunk_number in source.chunk_ids()? {
+    let scan = LogChunk::scan(&source.contents(chunk_number)?, keyring)?;
+    if cut.is_none() {
+      cut = Some((chunk_number, scan.header_size));
+    }
+    let events = match scan.events {
+      Some(events) => events,
+      None => return Err(format!("Chunk {} can't be decoded", chunk_number))
+    };
+
+    for (event, record_end) in events.iter().zip(scan.record_ends) {
+      // Chunks from before commit records have every event appended on its own.
+      let is_commit = event.event_type == COMMIT_EVENT_TYPE;
+      if !is_commit {
+        let timestamp = pending.map_or(event.timestamp, |(_, timestamp)| timestamp.max(event.timestamp));
+        pending = Some((event.id, timestamp));
+      }
+      if !is_commit && scan.version >= 3 {
+        continue;
+      }
+
+      if let Some((id, timestamp)) = pending.take() {
+        if until.is_before(id, timestamp) {
+          return Ok(cut.unwrap());
+        }
+      }
+      cut = Some((chunk_number, record_end));
+    }
+  }
+
+  match cut {
+    Some(cut) => Ok(cut),
+    None => Err("No chunks to restore".to_string())
+  }
+}
+
+// Works out which backup each chunk of the last backup's manifest is to be restored from.
+fn restore_sources(backup_dirs: &[&str], archive: Option<Arc<dyn Archive>>) -> Result<ChunkSource, String> {
+  if let [data_dir] = backup_dirs {
+    if !Path::new(&format!("{}/{}", data_dir, MANIFEST_FILE)).exists() {
+      return store_chunks(data_dir, archive);
+    }
+  }
+  // Backups have the archived chunks copied into them.
+  if archive.is_some() {
+    return Err("An archive can only be given when restoring from a data directory".to_string());
+  }
+
+  let manifests = backup_dirs.iter()
+    .map(|backup_dir| Manifest::read(backup_dir))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  if manifests.is_empty() {
+    return Err("No backup to restore".to_string());
+  }
+  if let Some(previous_id) = &manifests[0].previous_id {
+    return Err(format!("{} is an incremental backup, it needs the backup {} before it", backup_dirs[0], previous_id));
+  }
+  for (i, manifest) in manifests.iter().enumerate().skip(1) {
+    if manifest.previous_id.as_ref() != Some(&manifests[i - 1].id) {
+      return Err(format!("{} doesn't follow on from {}", backup_dirs[i], backup_dirs[i - 1]));
+    }
+  }
+
+  let mut chunk_files = BTreeMap::new();
+  for chunk in &manifests.last().unwrap().chunks {
+    // The most recent copy of the chunk, checked against the hash it's listed with.
+    let source = manifests.iter().zip(backup_dirs).rev().find_map(|(manifest, backup_dir)| {
+      manifest.chunks.iter()
+        .find(|copy| copy.id == chunk.id && copy.copied && copy.hash == chunk.hash)
+        .map(|_| chunk_path(backup_dir, chunk.id))
+    });
+    let source = match source {
+      Some(source) => source,
+      None => return Err(format!("Chunk {} isn't in any of the backups", chunk.id))
+    };
+
+    if hex::encode(LogChunk::stored_hash(&fs::read(&source).map_err(|error| error.to_string())?)?) != chunk.hash {
+      return Err(format!("{} is not the chunk listed in the manifest", source));
+    }
+    chunk_files.insert(chunk.id, source);
+  }
+
+  Ok(ChunkSource::Files(chunk_files))
+}
+
+fn load_keyring(key_file: Option<&str>) -> Result<Keyring, String> {
+  match key_file {
+    Some(key_file) => Keyring::load(key_file),
+    None => Ok(Keyring::empty())
+  }
+}
+
+fn chunk_path(data_dir: &str, chunk_number: u32) -> String {
+  format!("{}/chunks/{}.chk", data_dir, chunk_number)
+}
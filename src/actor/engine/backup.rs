@@ -0,0 +1,146 @@
+use std::fs::{self, File};
+use std::io::Write;
+
+use chrono::Utc;
+use ring::digest::SHA256_OUTPUT_LEN;
+use serde::{Serialize, Deserialize};
+
+use super::super::super::api::BackupResponse;
+use super::chunk::LogChunk;
+use super::chunk_files::ChunkFiles;
+use super::keyring::Keyring;
+use super::stream_keys::StreamKeys;
+
+// Backups are laid out like a data directory, so restoring one is mostly copying it back:
+//   <target>/chunks/<n>.chk   the chunks copied, the last one as of its last flush
+//   <target>/stream_keys/     the per-stream keys, when the server keeps them
+//   <target>/manifest.json    every chunk of the store at the time and the backup it's copied in
+// The index isn't kept on disk, it's rebuilt from the chunks on restore as on every start.
+// Shredding a stream doesn't reach backups taken before it, their copy of its key still opens its
+// payloads.
+// An incremental backup only copies the chunks that aren't in the backup it follows on from, or
+// have changed since, so restoring it takes every backup back to the last full one.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+  pub id          : String,
+  // Backup this one follows on from, None for a full backup.
+  pub previous_id : Option<String>,
+  pub created     : i64,
+  pub chunks      : Vec<ManifestChunk>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestChunk {
+  pub id     : u32,
+  // Hex encoded hash from the chunk's header, covering all of its contents.
+  pub hash   : String,
+  pub length : u64,
+  // Whether the chunk was full and no longer written to, only those are left out of later backups.
+  pub sealed : bool,
+  // Whether the chunk is in this backup, otherwise it's in one of the backups before it.
+  pub copied : bool
+}
+
+impl Manifest {
+  pub fn read(backup_dir: &str) -> Result<Self, String> {
+    let path     = format!("{}/{}", backup_dir, MANIFEST_FILE);
+    let contents = fs::read(&path).map_err(|error| format!("{}: {}", path, error))?;
+    serde_json::from_slice(&contents).map_err(|error| format!("{}: {}", path, error))
+  }
+
+  fn chunk(&self, id: u32) -> Option<&ManifestChunk> {
+    self.chunks.iter().find(|chunk| chunk.id == id)
+  }
+}
+
+// Copies the store while the writer thread keeps appending.  Every chunk but the last is full and
+// no longer written to, so they're copied as they are, archived ones fetched back to be.  With
+// since_dir, chunks that were already full in that backup are left out, without fetching the
+// archived ones.
+pub fn backup(chunk_files: &ChunkFiles, keyring: &Keyring, target_dir: &str, since_dir: Option<&str>, stream_keys: &StreamKeys) -> Result<BackupResponse, String> {
+  let previous = match since_dir {
+    Some(since_dir) => Some(Manifest::read(since_dir)?),
+    None => None
+  };
+
+  create_empty_dir(target_dir)?;
+  let target_chunks_dir = format!("{}/chunks", target_dir);
+  fs::create_dir(&target_chunks_dir).map_err(|error| error.to_string())?;
+
+  let chunk_ids = chunk_files.chunk_ids()?;
+  let mut chunks       = Vec::new();
+  let mut bytes_copied = 0;
+  for (i, chunk_id) in chunk_ids.iter().enumerate() {
+    let sealed = i < chunk_ids.len() - 1;
+
+    // Full chunks don't change, so one the previous backup has as full is taken as it is there.
+    let unchanged = previous.as_ref()
+      .and_then(|previous| previous.chunk(*chunk_id))
+      .filter(|chunk| chunk.sealed && sealed);
+    if let Some(unchanged) = unchanged {
+      if hex::encode(chunk_files.stored_hash(*chunk_id, keyring)?) != unchanged.hash {
+        return Err(format!("Chunk {} differs from the one in the previous backup", chunk_id));
+      }
+      chunks.push(ManifestChunk { copied: false, hash: unchanged.hash.clone(), ..*unchanged });
+      continue;
+    }
+
+    // A chunk the writer moves on from while this runs is copied as a snapshot all the same, it
+    // simply ends where it was last flushed when read.
+    let contents = match sealed {
+      true  => chunk_files.contents(*chunk_id)?,
+      false => LogChunk::snapshot(|| chunk_files.contents(*chunk_id))?
+    };
+    write_synced(&format!("{}/{}.chk", target_chunks_dir, chunk_id), &contents)?;
+    bytes_copied += contents.len() as u64;
+
+    chunks.push(ManifestChunk {
+      id     : *chunk_id,
+      hash   : hex::encode(&contents[.. SHA256_OUTPUT_LEN]),
+      length : contents.len() as u64,
+      sealed,
+      copied : true
+    });
+  }
+
+  // Keys are copied after the chunks, so every key anything copied was encrypted with is there.
+  // They're always copied in full, shredding changes them without adding any.  Keys of streams
+  // already shredded are copied shredded.
+  stream_keys.copy_to(&format!("{}/stream_keys", target_dir))?;
+
+  let manifest = Manifest {
+    id          : format!("{}-{:08x}", Utc::now().format("%Y%m%dT%H%M%S"), rand::random::<u32>()),
+    previous_id : previous.map(|previous| previous.id),
+    created     : Utc::now().timestamp(),
+    chunks
+  };
+
+  // The manifest goes last, a backup without one was never finished.
+  let serialized = serde_json::to_vec_pretty(&manifest).map_err(|error| error.to_string())?;
+  write_synced(&format!("{}/{}", target_dir, MANIFEST_FILE), &serialized)?;
+
+  Ok(BackupResponse {
+    chunks_copied    : manifest.chunks.iter().filter(|chunk| chunk.copied).count() as u32,
+    bytes_copied,
+    chunks_unchanged : manifest.chunks.iter().filter(|chunk| !chunk.copied).count() as u32,
+    backup_id        : manifest.id
+  })
+}
+
+// Creates dir if it doesn't exist yet, refusing to write over anything already in it.
+pub fn create_empty_dir(dir: &str) -> Result<(), String> {
+  fs::create_dir_all(dir).map_err(|error| error.to_string())?;
+  match fs::read_dir(dir).map_err(|error| error.to_string())?.next() {
+    Some(_) => Err(format!("{} is not empty", dir)),
+    None => Ok(())
+  }
+}
+
+// Written files are synced, a backup that's only in the page cache isn't one yet.
+pub fn write_synced(path: &str, contents: &[u8]) -> Result<(), String> {
+  let mut file = File::create(path).map_err(|error| error.to_string())?;
+  file.write_all(contents).map_err(|error| error.to_string())?;
+  file.sync_all().map_err(|error| error.to_string())
+}
@@ -1,22 +1,72 @@
-use std::fs::{self as fs, OpenOptions, File};
-use std::io::{Read, Write};
-use std::os::unix::prelude::FileExt;
+use std::fs;
+use std::sync::Arc;
 use chrono::Utc;
 use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
 use serde::{Serialize, Deserialize};
 use std::mem;
 use bincode;
+use std::ops::Range;
+use regex::Regex;
+
+use super::event::{Event, EventV1, EventV2};
+use super::keyring::{Keyring, ENCRYPTION_OVERHEAD};
+use super::merkle::{self, Hash};
+use super::storage::ChunkStorage;
+
+pub const MAX_CHUNK_SIZE: u32 = 1000000; // 1 MB
+// Times a chunk being written to is read before giving up on getting a consistent copy of it.
+const SNAPSHOT_ATTEMPTS: usize = 10;
+// Every record starts with its length and compression flag.
+const RECORD_HEADER_SIZE: u32 = mem::size_of::<u32>() as u32 + 1;
+// 2: events carry an event type, 3: appends end in a commit record, 4: records carry a compression flag,
+// 5: the header carries the id of the key records are encrypted with, 6: and the previous chunk's hash,
+// 7: commit records can carry the ids clients gave their events, 8: events carry metadata
+const HEADER_VERSION: u8 = 8;
+
+// How the event in a record is stored, kept in the byte following the record's length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+  None = 0,
+  Zstd = 1,
+  Lz4  = 2
+}
 
-use super::event::Event;
+impl Compression {
+  fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+    match self {
+      Compression::None => Ok(data.to_vec()),
+      Compression::Zstd => zstd::bulk::compress(data, 0).map_err(|error| format!("Failed to compress event: {}", error)),
+      Compression::Lz4  => Ok(lz4_flex::compress_prepend_size(data))
+    }
+  }
 
-const MAX_CHUNK_SIZE: u32 = 1000000; // 1 MB
-const HEADER_VERSION: u8 = 1;
+  fn decompress(flag: u8, data: &[u8]) -> Option<Vec<u8>> {
+    match flag {
+      0 => Some(data.to_vec()),
+      1 => zstd::stream::decode_all(data).ok(),
+      2 => lz4_flex::decompress_size_prepended(data).ok(),
+      _ => None
+    }
+  }
+}
+
+// Why an event wasn't written to the chunk.
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+  // The event has to go to the next chunk.
+  ChunkFull,
+  Compression(String)
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChunkHeader {
   hash      : [u8; SHA256_OUTPUT_LEN],
   version   : u8,
-  timestamp : i64
+  timestamp : i64,
+  // Key the chunk's records are encrypted with, 0 if they aren't.
+  key_id    : u32,
+  // Hash of the chunk before this one, chaining all chunks together.  Zero for the first chunk.
+  previous_hash : [u8; SHA256_OUTPUT_LEN]
 }
 
 impl ChunkHeader {
@@ -24,30 +74,74 @@ impl ChunkHeader {
     let header = Self {
       hash : [0; SHA256_OUTPUT_LEN],
       version   : HEADER_VERSION,
-      timestamp : Utc::now().timestamp()
+      timestamp : Utc::now().timestamp(),
+      key_id    : 0,
+      previous_hash : [0; SHA256_OUTPUT_LEN]
     };
     bincode::serialize(&header).unwrap().len() as u32
   }
+
+  // Returns the header along with its size, which depends on its version.  Fields have only ever
+  // been added at the end of the header, older headers simply stop earlier.
+  fn read(file_data: &[u8]) -> Option<(Self, usize)> {
+    let mut reader = file_data;
+
+    let hash      = bincode::deserialize_from(&mut reader).ok()?;
+    let version   = bincode::deserialize_from(&mut reader).ok()?;
+    let timestamp = bincode::deserialize_from(&mut reader).ok()?;
+    let key_id = match version {
+      0..=4 => 0,
+      _ => bincode::deserialize_from(&mut reader).ok()?
+    };
+    let previous_hash = match version {
+      0..=5 => [0; SHA256_OUTPUT_LEN],
+      _ => bincode::deserialize_from(&mut reader).ok()?
+    };
+
+    let header = Self {
+      hash,
+      version,
+      timestamp,
+      key_id,
+      previous_hash
+    };
+    Some((header, file_data.len() - reader.len()))
+  }
+}
+
+// What's found reading a chunk file without changing it, for checking chunks offline.
+pub struct ChunkScan {
+  pub version       : u8,
+  pub key_id        : u32,
+  pub previous_hash : [u8; SHA256_OUTPUT_LEN],
+  pub hash          : [u8; SHA256_OUTPUT_LEN],
+  pub file_len      : usize,
+  pub header_size   : usize,
+  // Where each of the hashed records ends.
+  pub record_ends   : Vec<usize>,
+  // Length of the chunk up to the last record boundary its hash matches, anything after that is
+  // a torn write.  None when the hash matches nowhere.
+  pub hashed_len    : Option<usize>,
+  // Events of the hashed records, None when they can't be decoded without the chunk's key.
+  pub events        : Option<Vec<Event>>
 }
 
 pub struct LogChunk {
   pub id      : u32,
+  pub version : u8,
+  pub key_id  : u32,
   pub offsets : Vec<u32>,
   available   : u32,
-  handle      : File,
+  storage     : Arc<dyn ChunkStorage>,
   context     : Context
 }
 
 impl LogChunk {
 
-  pub fn new(id: u32, path: &str) -> Self {
+  pub fn new(id: u32, storage: Arc<dyn ChunkStorage>, key_id: u32, previous_hash: [u8; SHA256_OUTPUT_LEN]) -> Self {
 
-    // Create file as log chunk.  Handle will close after going out of scope.
-    let mut handle = OpenOptions::new()
-      .read(true)
-      .append(true)
-      .create_new(true)
-      .open(&path).expect("Failed to create LogChunk file!");
+    // Create log chunk, failing if it's already there.
+    storage.create(id).expect("Failed to create LogChunk file!");
 
     // Setup hashing, hash starts out zero filled
     let hash = [0; SHA256_OUTPUT_LEN];
@@ -56,7 +150,9 @@ impl LogChunk {
     let mut header = ChunkHeader{
       hash,
       version   : HEADER_VERSION,
-      timestamp : Utc::now().timestamp()
+      timestamp : Utc::now().timestamp(),
+      key_id,
+      previous_hash
     };
 
     let mut serialized_header = bincode::serialize(&header).unwrap();
@@ -70,203 +166,466 @@ impl LogChunk {
     
     serialized_header = bincode::serialize(&header).unwrap();
 
-    handle.write_all(&serialized_header).expect("Failed to write header to LogChunk.");
-    handle.flush().expect("Failed to flush event to LogChunk.");
+    storage.append(id, &serialized_header).expect("Failed to write header to LogChunk.");
 
     Self {
       id,
+      version   : HEADER_VERSION,
+      key_id,
       offsets   : Vec::new(),
       available : MAX_CHUNK_SIZE-serialized_header.len() as u32,
-      handle,
+      storage,
       context
     }
   }
 
-  fn check_hash(context : &mut Context, file_data : &mut Vec<u8>) -> bool {
-    let mut file_hash : [u8; SHA256_OUTPUT_LEN] = [0; SHA256_OUTPUT_LEN];
-    let empty_hash : [u8; SHA256_OUTPUT_LEN]    = [0; SHA256_OUTPUT_LEN];
-    
-    file_hash.copy_from_slice(&file_data[ .. SHA256_OUTPUT_LEN]);
+  // Returns the chunk along with every event in it and the size of its record.
+  // The hash only covers what had been written when it was last flushed, anything after that was
+  // being written when the server stopped and is cut off the end of the chunk in storage.
+  // entire_file is what's in the chunk, which is only read from storage for chunks that are there.
+  pub fn index(id: u32, mut entire_file: Vec<u8>, storage: Arc<dyn ChunkStorage>, keyring: &Keyring) -> (Self, Vec<(Event, u32)>) {
+    let (header, header_size) = match ChunkHeader::read(&entire_file) {
+      Some(header) => header,
+      None => panic!("Corrupt log chunk header {}", id)
+    };
 
-    file_data.splice( .. SHA256_OUTPUT_LEN, empty_hash);
+    // Without the key every record would look unreadable, and be cut off as unfinished.
+    if !keyring.has_key(header.key_id) {
+      panic!("Log chunk {} is encrypted with key {} which is not in the key file", id, header.key_id);
+    }
 
-    context.update(file_data);
+    // The hash was calculated with the hash itself zero filled.
+    entire_file.splice( .. SHA256_OUTPUT_LEN, [0; SHA256_OUTPUT_LEN]);
+    let mut context = Context::new(&SHA256);
+    context.update(&entire_file[ .. header_size]);
 
-    let context_clone = context.clone();
+    // Find the last record boundary the hash matches: (length, number of records, context).
+    let mut valid    = None;
+    let mut records  = Vec::new();
+    let mut position = header_size;
+    loop {
+      if context.clone().finish().as_ref() == header.hash {
+        valid = Some((position, records.len(), context.clone()));
+      }
 
-    let calc_hash = context_clone.finish();
+      match LogChunk::read_record(&entire_file, position, &header, keyring) {
+        Some((event, size)) => {
+          context.update(&entire_file[position .. position + size]);
+          records.push((position as u32, event, size as u32));
+          position += size;
+        }
+        None => break
+      }
+    }
 
+    let (valid_len, record_count, context) = match valid {
+      Some(valid) => valid,
+      None => panic!("Corrupt log chunk {}", id)
+    };
 
-    for (i, val) in file_hash.iter().enumerate() {
-      if *val != calc_hash.as_ref()[i] {
-        println!("Found hash : {:02x?}", file_hash);
-        println!("Calc hash  : {:02x?}", calc_hash.as_ref());
+    if valid_len < entire_file.len() {
+      println!("Discarding {} bytes of unfinished writes from chunk {}", entire_file.len() - valid_len, id);
+      storage.truncate(id, valid_len as u64).expect("Failed to truncate LogChunk file!");
+    }
+    records.truncate(record_count);
 
-        return false;
-      }
+    if records.is_empty() {
+      println!("Empty chunk!");
     }
 
-    return true;
+    let offsets    = records.iter().map(|(offset, _, _)| *offset).collect();
+    let event_info = records.into_iter().map(|(_, event, size)| (event, size)).collect();
+
+    (
+      Self{
+        id,
+        version   : header.version,
+        key_id    : header.key_id,
+        offsets,
+        available : MAX_CHUNK_SIZE - valid_len as u32,
+        storage,
+        context
+      },
+      event_info
+    )
   }
 
-  pub fn index(id: u32, path: &str) -> (Self, Vec<(String, u64)>) {
-    let mut context = Context::new(&SHA256);
+  // Reads the record at position, returning the event and the size of the record.  None at the end
+  // of the chunk or for a record that was only partly written.
+  fn read_record(file_data: &[u8], position: usize, header: &ChunkHeader, keyring: &Keyring) -> Option<(Event, usize)> {
+    let (flag, encoded) = LogChunk::record_data(file_data, position, header.version)?;
+    let record_size = encoded.len() + LogChunk::record_header_size(header.version);
+
+    let decrypted = keyring.open(header.key_id, &LogChunk::associated_data(position as u32, flag), encoded)?;
+    let decoded   = Compression::decompress(flag, &decrypted)?;
+    let event     = match header.version {
+      0..=1 => bincode::deserialize::<EventV1>(&decoded).ok()?.into(),
+      2..=7 => bincode::deserialize::<EventV2>(&decoded).ok()?.into(),
+      _ => bincode::deserialize(&decoded).ok()?
+    };
+
+    Some((event, record_size))
+  }
 
-    let mut handle = OpenOptions::new()
-      .read(true)
-      .append(true)
-      .open(&path).expect("Failed to open LogChunk file!");
+  // Returns the compression flag and stored data of the record at position, without decoding it.
+  fn record_data(file_data: &[u8], position: usize, version: u8) -> Option<(u8, &[u8])> {
+    let len_size    = mem::size_of::<u32>();
+    let encoded_len : u32 = bincode::deserialize(file_data.get(position .. position + len_size)?).ok()?;
 
-    let mut entire_file      = Vec::with_capacity(MAX_CHUNK_SIZE as usize);
-    let chunk_len = handle.read_to_end(entire_file.as_mut());
+    // Records from before HEADER_VERSION 4 have no compression flag.
+    let flag = match version {
+      0..=3 => Compression::None as u8,
+      _ => *file_data.get(position + len_size)?
+    };
+
+    let start = position + LogChunk::record_header_size(version);
+    Some((flag, file_data.get(start .. start + encoded_len as usize)?))
+  }
 
-    if !LogChunk::check_hash(
-      &mut context, 
-      &mut entire_file
-    ) {
-      panic!("Corrupt log chunk {}", path);
+  fn record_header_size(version: u8) -> usize {
+    match version {
+      0..=3 => mem::size_of::<u32>(),
+      _ => RECORD_HEADER_SIZE as usize
     }
+  }
 
-    let header_size = ChunkHeader::size_of();
-    let mut position  : u32 = header_size;
-    let mut remaining : u32 = chunk_len.unwrap() as u32 - header_size;
-    let available   = MAX_CHUNK_SIZE - remaining - header_size;
+  // Encrypted records are tied to their offset and compression flag, so they can't be moved
+  // around in the chunk or have their flag changed without failing to decrypt.
+  fn associated_data(offset: u32, flag: u8) -> Vec<u8> {
+    let mut associated_data = offset.to_le_bytes().to_vec();
+    associated_data.push(flag);
+    associated_data
+  }
 
-    let mut event_info = Vec::new();
-    let mut offsets         = Vec::new();
+  // Chunks from before HEADER_VERSION 3 have no commit records, every event in them counts as committed.
+  pub fn has_commit_records(&self) -> bool {
+    self.version >= 3
+  }
 
-    if remaining == 0 {
-      println!("Empty chunk!");
-      return (
-        Self{
-          id,
-          offsets,
-          available,
-          handle,
-          context
-        },
-        event_info
-      );
+  // Only chunks in the current format are written to, records of older versions are laid out differently.
+  pub fn is_current_version(&self) -> bool {
+    self.version == HEADER_VERSION
+  }
+
+  // Hash of everything written so far, what the next chunk chains on to.
+  pub fn hash(&self) -> [u8; SHA256_OUTPUT_LEN] {
+    let mut hash = [0; SHA256_OUTPUT_LEN];
+    hash.copy_from_slice(self.context.clone().finish().as_ref());
+    hash
+  }
+
+  // Checks a chunk's contents against its hash, and that it chains on to previous_hash, returning
+  // its hash for checking the next chunk.  Chunks from before the chain only have their own hash
+  // checked.  The chunk still being written to can have records after its last flush, which are
+  // only allowed with unflushed.
+  pub fn verify(entire_file: &[u8], previous_hash: &[u8; SHA256_OUTPUT_LEN], unflushed: bool) -> Result<[u8; SHA256_OUTPUT_LEN], String> {
+    let (header, header_size) = match ChunkHeader::read(entire_file) {
+      Some(header) => header,
+      None => return Err("Chunk header is unreadable".to_string())
+    };
+
+    if header.version >= 6 && header.previous_hash != *previous_hash {
+      return Err("Previous hash does not match the hash of the chunk before it".to_string());
+    }
+
+    let hashed_len = LogChunk::hashed_records(entire_file, &header, header_size)
+      .map(|records| records.last().map_or(header_size, |record| record.end));
+
+    match hashed_len {
+      Some(hashed_len) if hashed_len == entire_file.len() || unflushed => Ok(header.hash),
+      _ => Err("Contents do not match the chunk's hash".to_string())
+    }
+  }
+
+  // Reads the chunk without changing it, Err only when it can't be read at all.
+  pub fn scan(entire_file: &[u8], keyring: &Keyring) -> Result<ChunkScan, String> {
+    let (header, header_size) = match ChunkHeader::read(entire_file) {
+      Some(header) => header,
+      None => return Err("Chunk header is unreadable".to_string())
+    };
+
+    let records    = LogChunk::hashed_records(entire_file, &header, header_size);
+    let hashed_len = records.as_ref().map(|records| records.last().map_or(header_size, |record| record.end));
+
+    let events = match (&records, keyring.has_key(header.key_id)) {
+      (Some(records), true) => records.iter()
+        .map(|record| LogChunk::read_record(entire_file, record.start, &header, keyring).map(|(event, _)| event))
+        .collect(),
+      _ => None
+    };
+
+    Ok(ChunkScan {
+      version       : header.version,
+      key_id        : header.key_id,
+      previous_hash : header.previous_hash,
+      hash          : header.hash,
+      file_len      : entire_file.len(),
+      header_size,
+      record_ends   : records.iter().flatten().map(|record| record.end).collect(),
+      hashed_len,
+      events
+    })
+  }
+
+  // Cuts the chunk's contents off at length, which has to be a record boundary, and rehashes them.
+  pub fn truncate(contents: &mut Vec<u8>, length: usize) -> Result<(), String> {
+    match ChunkHeader::read(contents) {
+      Some((_, header_size)) if header_size <= length => {}
+      _ => return Err("Chunk header is unreadable".to_string())
     }
+    contents.truncate(length);
 
+    // The hash is calculated with the hash itself zero filled.
+    let mut context = Context::new(&SHA256);
+    context.update(&[0; SHA256_OUTPUT_LEN]);
+    context.update(&contents[SHA256_OUTPUT_LEN ..]);
+    contents[.. SHA256_OUTPUT_LEN].copy_from_slice(context.finish().as_ref());
+    Ok(())
+  }
 
+  // The hash stored in the chunk's header.
+  pub fn stored_hash(entire_file: &[u8]) -> Result<[u8; SHA256_OUTPUT_LEN], String> {
+    let mut hash = [0; SHA256_OUTPUT_LEN];
+    match entire_file.get(.. SHA256_OUTPUT_LEN) {
+      Some(stored) => hash.copy_from_slice(stored),
+      None => return Err("Chunk header is unreadable".to_string())
+    }
+    Ok(hash)
+  }
+
+  // Contents of a chunk that may still be written to, up to where it was last flushed, read with
+  // read.  The chunk is read from the start, so the hash read comes from a flush that finished
+  // before the records it covers were read, except when the hash is being rewritten as it's read;
+  // then it matches no record boundary and the chunk is read again.
+  pub fn snapshot(read: impl Fn() -> Result<Vec<u8>, String>) -> Result<Vec<u8>, String> {
+    for _ in 0 .. SNAPSHOT_ATTEMPTS {
+      let mut entire_file = read()?;
+      let (header, header_size) = match ChunkHeader::read(&entire_file) {
+        Some(header) => header,
+        None => continue
+      };
+
+      if let Some(records) = LogChunk::hashed_records(&entire_file, &header, header_size) {
+        entire_file.truncate(records.last().map_or(header_size, |record| record.end));
+        return Ok(entire_file);
+      }
+    }
+
+    Err(format!("No consistent snapshot after {} attempts", SNAPSHOT_ATTEMPTS))
+  }
+
+  // Offsets and Merkle leaf hashes of the chunk's records, up to where it was last flushed.
+  pub fn record_leaves(entire_file: &[u8]) -> Result<Vec<(u32, Hash)>, String> {
+    let (header, header_size) = match ChunkHeader::read(entire_file) {
+      Some(header) => header,
+      None => return Err("Chunk header is unreadable".to_string())
+    };
+
+    match LogChunk::hashed_records(entire_file, &header, header_size) {
+      Some(records) => Ok(records.into_iter()
+        .map(|record| (record.start as u32, merkle::leaf_hash(&entire_file[record])))
+        .collect()),
+      None => Err("Contents do not match the chunk's hash".to_string())
+    }
+  }
+
+  // The record at offset as it is stored, length and flag included.
+  pub fn read_raw_record(entire_file: &[u8], offset: u32) -> Result<Vec<u8>, String> {
+    let (header, _) = match ChunkHeader::read(entire_file) {
+      Some(header) => header,
+      None => return Err("Chunk header is unreadable".to_string())
+    };
+
+    match LogChunk::record_data(entire_file, offset as usize, header.version) {
+      Some((_, encoded)) => {
+        let record_end = offset as usize + LogChunk::record_header_size(header.version) + encoded.len();
+        Ok(entire_file[offset as usize .. record_end].to_vec())
+      }
+      None => Err(format!("No record at offset {}", offset))
+    }
+  }
+
+  // Byte ranges of the records the header's hash covers, None if it matches at no record boundary.
+  // Records are only framed, not decoded, so this works without the chunk's key.
+  fn hashed_records(file_data: &[u8], header: &ChunkHeader, header_size: usize) -> Option<Vec<Range<usize>>> {
+    // The hash was calculated with the hash itself zero filled.
+    let mut context = Context::new(&SHA256);
+    context.update(&[0; SHA256_OUTPUT_LEN]);
+    context.update(&file_data[SHA256_OUTPUT_LEN .. header_size]);
+
+    let mut hashed   = None;
+    let mut records  = Vec::new();
+    let mut position = header_size;
     loop {
-      offsets.push(position);
-
-      // Read each event by first reading its length then the event itself from the chunk
-      let range_len     = std::ops::Range{ start: position as usize, end: (position + 4) as usize};
-      let encoded_len : u32         = bincode::deserialize(entire_file.get(range_len).unwrap()).unwrap();
-      position += mem::size_of::<u32>() as u32;
-
-      let range_encoded = std::ops::Range{ start: position as usize, end: (position + encoded_len) as usize};
-      let event_data_result : Option<&[u8]>= entire_file.get(range_encoded);
-
-      // Error handling
-      match event_data_result {
-        Some(slice) => {
-          let event_result : Result<Event, _> = bincode::deserialize(slice);
-          match event_result {
-            Ok(event) => {
-              event_info.push((event.name, event.id));
-            }
-            Err(e) => {
-              panic!("Failed to deserialize the event data: {}", e);
-            }
-          }
-        }
-        None => {
-          panic!("Failed to find event data in range specified {} {}", position, encoded_len);
+      if context.clone().finish().as_ref() == header.hash {
+        hashed = Some(records.len());
+      }
+
+      match LogChunk::record_data(file_data, position, header.version) {
+        Some((_, encoded)) => {
+          let record_end = position + LogChunk::record_header_size(header.version) + encoded.len();
+          context.update(&file_data[position .. record_end]);
+          records.push(position .. record_end);
+          position = record_end;
         }
+        None => break
       }
+    }
 
-      position  += encoded_len;
-      remaining -= encoded_len + mem::size_of::<u32>() as u32;
+    hashed.map(|count| {
+      records.truncate(count);
+      records
+    })
+  }
+
+  // Ids of the chunk files in dir, in order.
+  pub fn chunk_ids(dir: &str) -> Result<Vec<u32>, String> {
+    let re = Regex::new(r"^(\d+)\.chk$").unwrap();
 
-      if remaining == 0 {
-        break;
+    let mut chunk_ids = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|error| error.to_string())? {
+      let file_name = entry.map_err(|error| error.to_string())?.file_name();
+      if let Some(captures) = file_name.to_str().and_then(|file_name| re.captures(file_name)) {
+        chunk_ids.push(captures[1].parse().unwrap());
       }
     }
+    chunk_ids.sort();
 
-    (
-      Self{
-        id,
-        offsets,
-        available,
-        handle,
-        context
-      },
-      event_info
-    )
+    Ok(chunk_ids)
   }
 
-  fn flush_chunk(&mut self) {
+  // Writes the hash of everything written so far and syncs the chunk to disk.
+  pub fn flush_chunk(&mut self) {
     let context_clone = self.context.clone();
     let new_hash = context_clone.finish();
-    let _ = self.handle.write_at(new_hash.as_ref(), 0).unwrap();
+    self.storage.write_at(self.id, 0, new_hash.as_ref()).unwrap();
+    self.storage.sync(self.id).expect("Failed to sync LogChunk.");
+  }
 
-    self.handle.flush().expect("Failed to flush event to LogChunk.");
+  // Whether the event's record fits in an empty chunk, even uncompressed and encrypted.
+  pub fn fits_in_chunk(event: &Event) -> bool {
+    let record_size = bincode::serialized_size(event).unwrap() + (RECORD_HEADER_SIZE as usize + ENCRYPTION_OVERHEAD) as u64;
+    record_size <= (MAX_CHUNK_SIZE - ChunkHeader::size_of()) as u64
   }
 
-  pub fn attempt_to_write_event(&mut self, event: &Event, flush: bool) -> Result<u32, &'static str> {
+  // Returns the offset the event was written at and the size of its record.  Nothing is flushed
+  // until flush_chunk is called, except when the chunk is full.
+  // The event is only stored compressed if that makes it smaller, and is then encrypted with the
+  // chunk's key if it has one.
+  pub fn attempt_to_write_event(&mut self, event: &Event, compression: Compression, keyring: &Keyring) -> Result<(u32, u32), WriteError> {
 
     // Serialize the event to bytes
     let mut encoded = bincode::serialize(event).unwrap();
+    let mut flag    = Compression::None;
+    if compression != Compression::None {
+      let compressed = compression.compress(&encoded).map_err(WriteError::Compression)?;
+      if compressed.len() < encoded.len() {
+        encoded = compressed;
+        flag    = compression;
+      }
+    }
+    let offset = MAX_CHUNK_SIZE - self.available;
+    let mut encoded = keyring.seal(self.key_id, &LogChunk::associated_data(offset, flag as u8), &encoded);
+
     let encoded_len = encoded.len() as u32;
+    let record_size = encoded_len + RECORD_HEADER_SIZE;
 
     // Can it fit?
-    if encoded_len + mem::size_of::<u32>() as u32 > self.available {
+    if record_size > self.available {
       // flush writes to current chunk just in case before returning.
       self.flush_chunk();
-      return Err("No space left in this chunk.");
+      return Err(WriteError::ChunkFull);
     }
 
-    let offset = MAX_CHUNK_SIZE - self.available;
     self.offsets.push(offset);
 
-    // Length followed by payload in bincode format.
-    //println!("Writing {} bytes + 4 in chunk {}", encoded_len, self.id);
-    let mut payload: Vec<u8> = Vec::with_capacity((encoded_len + mem::size_of::<u32>() as u32) as usize);
+    // Length and compression flag followed by payload in bincode format.
+    let mut payload: Vec<u8> = Vec::with_capacity(record_size as usize);
     payload.append(bincode::serialize(&encoded_len).as_mut().unwrap());
+    payload.push(flag as u8);
     payload.append(encoded.as_mut());
 
-    self.handle.write_all(&payload).expect("Failed to write event to LogChunk.");
+    self.storage.append(self.id, &payload).expect("Failed to write event to LogChunk.");
     self.context.update(&payload);
-    if flush {
-      self.flush_chunk();
-    }
-    self.available -= encoded_len + mem::size_of::<u32>() as u32;
+    self.available -= record_size;
 
-    Ok(offset)
+    Ok((offset, record_size))
   }
 
-  pub fn stream_events_out(offset: u32, path: &str) -> Result<Vec<Event>, std::io::Error> {
-    let entire_file = fs::read(path)?;
-    let chunk_len     = entire_file.len();
+  // Returns every event from offset to the end of the chunk paired with the offset it was read from.
+  // A record still being written at the end of the chunk is left out.
+  pub fn stream_events_out(offset: u32, entire_file: &[u8], keyring: &Keyring) -> Result<Vec<(u32, Event)>, String> {
+    let (header, _) = match ChunkHeader::read(entire_file) {
+      Some(header) => header,
+      None => return Err("Corrupt log chunk header".to_string())
+    };
 
-    let mut position  : u32 = offset;
-    let mut remaining : u32 = chunk_len as u32 - offset;
+    let mut position = offset as usize;
+    let mut events   = Vec::new();
 
-    let mut events = Vec::new();
+    while let Some((event, size)) = LogChunk::read_record(entire_file, position, &header, keyring) {
+      events.push((position as u32, event));
+      position += size;
+    }
 
-    loop {
-      let range_len = std::ops::Range{ start: position as usize, end: (position + 4) as usize};
-      let encoded_len : u32     = bincode::deserialize(entire_file.get(range_len).unwrap()).unwrap();
+    Ok(events)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use super::*;
+  use super::super::storage::MemoryStorage;
 
-      position += mem::size_of::<u32>() as u32;
+  fn event(id: u64, payload: &str) -> Event {
+    Event::new(id, "orders", "Added", payload).unwrap()
+  }
+
+  fn payloads(events: &[(u32, Event)]) -> Vec<&str> {
+    events.iter().map(|(_, event)| event.payload.as_str()).collect()
+  }
 
-      let range_encoded = std::ops::Range{ start: position as usize, end: (position + encoded_len) as usize};
-      let event : Event             = bincode::deserialize(entire_file.get(range_encoded).unwrap()).unwrap();
+  #[test]
+  fn records_are_read_back_however_they_were_compressed() {
+    let storage = Arc::new(MemoryStorage::new());
+    let keyring = Keyring::empty();
+    let mut chunk = LogChunk::new(1, storage.clone(), 0, [0; SHA256_OUTPUT_LEN]);
+
+    let long    = "abc".repeat(100);
+    let written = [Compression::None, Compression::Zstd, Compression::Lz4];
+    let mut offsets = Vec::new();
+    for (id, compression) in written.iter().enumerate() {
+      let (offset, _) = chunk.attempt_to_write_event(&event(id as u64, &long), *compression, &keyring).unwrap();
+      offsets.push(offset);
+    }
+    chunk.flush_chunk();
 
-      events.push(event);
+    let contents = storage.read(1).unwrap();
+    for (offset, compression) in offsets.iter().zip(written.iter()) {
+      let (flag, _) = LogChunk::record_data(&contents, *offset as usize, HEADER_VERSION).unwrap();
+      assert_eq!(flag, *compression as u8);
+    }
 
-      position  += encoded_len;
-      remaining -= encoded_len + mem::size_of::<u32>() as u32;
+    let events = LogChunk::stream_events_out(offsets[0], &contents, &keyring).unwrap();
+    assert_eq!(payloads(&events), [long.as_str(), &long, &long]);
+  }
 
-      if remaining == 0 {
-        break;
-      }
+  #[test]
+  fn records_from_before_compression_flags_are_read() {
+    // Version 3 header: hash, version and timestamp.  Records are only a length and the event.
+    let mut contents = vec![0; SHA256_OUTPUT_LEN];
+    contents.push(3);
+    contents.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+    let header_size = contents.len() as u32;
+    for (id, payload) in ["o0", "o1"].iter().enumerate() {
+      // Events had no metadata yet.
+      let encoded = bincode::serialize(&(id as u64, 1_700_000_000i64, "orders", payload, "Added")).unwrap();
+      contents.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+      contents.extend_from_slice(&encoded);
     }
 
-    Ok(events)
+    let events = LogChunk::stream_events_out(header_size, &contents, &Keyring::empty()).unwrap();
+    assert_eq!(payloads(&events), ["o0", "o1"]);
   }
 }
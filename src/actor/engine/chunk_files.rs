@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ring::digest::SHA256_OUTPUT_LEN;
+
+use super::archive::Archive;
+use super::chunk::LogChunk;
+use super::keyring::Keyring;
+use super::storage::ChunkStorage;
+use super::summary::ChunkSummary;
+
+// Where chunks fetched back from the archive are kept unless the engine is given another directory.
+pub const CACHE_DIR: &str = "chunk_cache";
+
+// Where the summaries of archived chunks are kept unless the engine is given another directory, so
+// they can be indexed without fetching them.
+pub const INDEX_DIR: &str = "chunk_index";
+
+// Numbers the files written and then renamed into place, to give each its own name to write to.
+static FETCHES: AtomicU64 = AtomicU64::new(0);
+
+// Finds the contents of a chunk, which are in storage unless the chunk has been archived.
+// Archived chunks are fetched back into the cache directory as they're read, the least recently
+// read ones making way once it holds cache_size of them.
+pub struct ChunkFiles {
+  storage    : Arc<dyn ChunkStorage>,
+  archive    : Option<Arc<dyn Archive>>,
+  cache_dir  : String,
+  index_dir  : String,
+  cache_size : usize,
+  // Chunks in the cache, least recently read first.  Only locked to look at or change the list,
+  // never while reading or writing a chunk, so a chunk read at the same time by several readers
+  // may be fetched by each of them.
+  cached     : Mutex<VecDeque<u32>>
+}
+
+impl ChunkFiles {
+  // Every chunk is in storage.
+  pub fn new(storage: Arc<dyn ChunkStorage>) -> Self {
+    Self {
+      storage,
+      archive    : None,
+      cache_dir  : String::new(),
+      index_dir  : String::new(),
+      cache_size : 0,
+      cached     : Mutex::new(VecDeque::new())
+    }
+  }
+
+  // Chunks already in the cache, from an earlier run or another process using the same one, are
+  // read from it like any fetched since; archived chunks never change.  Nothing is deleted until
+  // more than cache_size chunks have been read.
+  pub fn with_archive(storage: Arc<dyn ChunkStorage>, archive: Arc<dyn Archive>, cache_dir: &str, index_dir: &str, cache_size: usize) -> Result<Self, String> {
+    fs::create_dir_all(cache_dir).map_err(|error| format!("{}: {}", cache_dir, error))?;
+    fs::create_dir_all(index_dir).map_err(|error| format!("{}: {}", index_dir, error))?;
+
+    let mut cached : Vec<u32> = fs::read_dir(cache_dir).map_err(|error| format!("{}: {}", cache_dir, error))?
+      .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_suffix(".chk")?.parse().ok())
+      .collect();
+    cached.sort();
+
+    Ok(Self {
+      storage,
+      archive    : Some(archive),
+      cache_dir  : cache_dir.to_string(),
+      index_dir  : index_dir.to_string(),
+      cache_size : cache_size.max(1),
+      cached     : Mutex::new(cached.into())
+    })
+  }
+
+  pub fn storage(&self) -> Arc<dyn ChunkStorage> {
+    self.storage.clone()
+  }
+
+  // Chunk numbers of every chunk, whether in storage or archived, in order.
+  pub fn chunk_ids(&self) -> Result<Vec<u32>, String> {
+    let mut chunk_ids = self.storage.list()?;
+    if let Some(archive) = &self.archive {
+      chunk_ids.extend(archive.list()?);
+      chunk_ids.sort();
+      chunk_ids.dedup();
+    }
+
+    Ok(chunk_ids)
+  }
+
+  pub fn is_local(&self, chunk_number: u32) -> bool {
+    self.storage.exists(chunk_number)
+  }
+
+  // Everything in the chunk, fetched from the archive when it's no longer in storage.  A chunk
+  // archived while this runs is no longer in storage by the time it's read, and is fetched as well.
+  pub fn contents(&self, chunk_number: u32) -> Result<Vec<u8>, String> {
+    let archive = match (self.storage.read(chunk_number), &self.archive) {
+      (Ok(contents), _) => return Ok(contents),
+      (Err(error), None) => return Err(error),
+      (Err(_), Some(archive)) => archive
+    };
+
+    let cache_path = format!("{}/{}.chk", self.cache_dir, chunk_number);
+    if self.touch(chunk_number) {
+      // It may have been evicted since, then it's fetched again.
+      if let Ok(contents) = fs::read(&cache_path) {
+        return Ok(contents);
+      }
+    }
+
+    let contents = archive.get(chunk_number).map_err(|error| format!("Failed to fetch chunk {}: {}", chunk_number, error))?;
+
+    // Written under a name of its own and renamed into place, so a reader never sees half of it.
+    let fetch_path = format!("{}.{}", cache_path, FETCHES.fetch_add(1, Ordering::Relaxed));
+    fs::write(&fetch_path, &contents).map_err(|error| format!("{}: {}", fetch_path, error))?;
+    fs::rename(&fetch_path, &cache_path).map_err(|error| format!("{}: {}", cache_path, error))?;
+
+    let evicted = {
+      let mut cached = self.cached.lock().unwrap();
+      if !cached.contains(&chunk_number) {
+        cached.push_back(chunk_number);
+      }
+      let excess = cached.len().saturating_sub(self.cache_size);
+      cached.drain(.. excess).collect::<Vec<u32>>()
+    };
+    for evicted in evicted {
+      fs::remove_file(format!("{}/{}.chk", self.cache_dir, evicted)).ok();
+    }
+
+    Ok(contents)
+  }
+
+  // Marks the chunk as the most recently read if it's in the cache, returning whether it is.
+  fn touch(&self, chunk_number: u32) -> bool {
+    let mut cached = self.cached.lock().unwrap();
+    match cached.iter().position(|cached_chunk| *cached_chunk == chunk_number) {
+      Some(i) => {
+        cached.remove(i);
+        cached.push_back(chunk_number);
+        true
+      }
+      None => false
+    }
+  }
+
+  // Summary of an archived chunk, None when there is none or it can't be opened.
+  pub fn summary(&self, chunk_number: u32, keyring: &Keyring) -> Option<ChunkSummary> {
+    self.archive.as_ref()?;
+
+    let sealed = fs::read(format!("{}/{}.idx", self.index_dir, chunk_number)).ok()?;
+    ChunkSummary::open(chunk_number, &sealed, keyring)
+  }
+
+  // Keeps the summary of a full chunk's contents, nothing for a chunk that can't be summarized.
+  pub fn write_summary(&self, chunk_number: u32, contents: &[u8], keyring: &Keyring) -> Result<(), String> {
+    let summary = match (&self.archive, ChunkSummary::of(contents, keyring)) {
+      (Some(_), Some(summary)) => summary,
+      _ => return Ok(())
+    };
+
+    // Renamed into place like fetched chunks, a summary is either all there or not at all.
+    let index_path = format!("{}/{}.idx", self.index_dir, chunk_number);
+    let write_path = format!("{}.{}", index_path, FETCHES.fetch_add(1, Ordering::Relaxed));
+    fs::write(&write_path, summary.seal(chunk_number, keyring)).map_err(|error| format!("{}: {}", write_path, error))?;
+    fs::rename(&write_path, &index_path).map_err(|error| format!("{}: {}", index_path, error))
+  }
+
+  // The hash in the chunk's header, taken from its summary when it's archived and has one.
+  pub fn stored_hash(&self, chunk_number: u32, keyring: &Keyring) -> Result<[u8; SHA256_OUTPUT_LEN], String> {
+    if !self.is_local(chunk_number) {
+      if let Some(summary) = self.summary(chunk_number, keyring) {
+        return Ok(summary.hash);
+      }
+    }
+
+    self.read(chunk_number, LogChunk::stored_hash)
+  }
+
+  // Runs read on the chunk's contents.
+  pub fn read<T>(&self, chunk_number: u32, read: impl FnOnce(&[u8]) -> Result<T, String>) -> Result<T, String> {
+    read(&self.contents(chunk_number)?)
+  }
+
+  // Moves every chunk but the last keep_local to the archive.  Only the writer's chunk, the last
+  // one, is written to, so keeping at least that one every chunk archived is full.  A chunk is only
+  // removed from storage once the archive gives back what was put in it and its summary is written.
+  // Returns the number of chunks archived.
+  pub fn archive_sealed(&self, keep_local: usize, keyring: &Keyring) -> Result<usize, String> {
+    let archive = match &self.archive {
+      Some(archive) => archive,
+      None => return Ok(0)
+    };
+
+    let chunk_ids = self.storage.list()?;
+    let sealed    = chunk_ids.len().saturating_sub(keep_local.max(1));
+    for chunk_number in &chunk_ids[.. sealed] {
+      let contents = self.storage.read(*chunk_number)?;
+
+      archive.put(*chunk_number, &contents)?;
+      if archive.get(*chunk_number)? != contents {
+        return Err(format!("Chunk {} came back from the archive different from what was put there", chunk_number));
+      }
+      self.write_summary(*chunk_number, &contents, keyring)?;
+
+      self.storage.remove(*chunk_number)?;
+      println!("Archived chunk {}", chunk_number);
+    }
+
+    Ok(sealed)
+  }
+}
@@ -1,32 +1,179 @@
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use chrono::Utc;
 
+// A link event points at another stream's event instead of carrying data of its own.  Its payload
+// is "<revision>@<stream name>".
+pub const LINK_EVENT_TYPE: &str = "$>";
+
+// Every append ends in a commit record, an event of this type whose payload is the number of
+// events the append wrote.  Commit records belong to no stream.  When any of the events has an id
+// given by the client, the ids follow as a JSON array, null for events without one:
+//   3 ["order-1-placed",null,"order-1-paid"]
+pub const COMMIT_EVENT_TYPE: &str = "$commit";
+
+// A stream's metadata and deletion are recorded as events of these types in $$<stream name>.  The
+// latest metadata event's payload, a JSON document, is the stream's metadata.
+pub const METADATA_EVENT_TYPE: &str = "$metadata";
+pub const STREAM_DELETED_EVENT_TYPE: &str = "$streamDeleted";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Event {
+    pub id         : u64,
+    pub timestamp  : i64,
+    pub name       : String,
+    pub payload    : String,
+    pub event_type : String,
+    // Free-form, such as the correlation and causation ids other stores keep.  Empty for none.
+    pub metadata   : String
+}
+
+// Events as chunks of HEADER_VERSION 1 hold them, from before events had a type.
+#[derive(Deserialize)]
+pub struct EventV1 {
     pub id        : u64,
     pub timestamp : i64,
     pub name      : String,
     pub payload   : String
 }
 
+impl From<EventV1> for Event {
+    fn from(event: EventV1) -> Self {
+        Self {
+            id         : event.id,
+            timestamp  : event.timestamp,
+            name       : event.name,
+            payload    : event.payload,
+            event_type : String::new(),
+            metadata   : String::new()
+        }
+    }
+}
+
+// Events as chunks of HEADER_VERSION 2 to 7 hold them, from before events had metadata.
+#[derive(Deserialize)]
+pub struct EventV2 {
+    pub id         : u64,
+    pub timestamp  : i64,
+    pub name       : String,
+    pub payload    : String,
+    pub event_type : String
+}
+
+impl From<EventV2> for Event {
+    fn from(event: EventV2) -> Self {
+        Self {
+            id         : event.id,
+            timestamp  : event.timestamp,
+            name       : event.name,
+            payload    : event.payload,
+            event_type : event.event_type,
+            metadata   : String::new()
+        }
+    }
+}
+
+// An event handed to the engine to be appended, before it has been given an id.
+#[derive(Debug, Clone)]
+pub struct ProposedEvent {
+    pub event_type : String,
+    pub payload    : String,
+    // When the event happened, for events imported from elsewhere.  None for the time of writing.
+    pub timestamp  : Option<i64>,
+    // Chosen by the client so that retrying an append doesn't write the event twice.
+    pub event_id   : Option<String>,
+    pub metadata   : String
+}
+
+impl ProposedEvent {
+    // An event timestamped when it's written.
+    pub fn new(event_type: &str, payload: &str) -> Self {
+        Self {
+            event_type : event_type.to_string(),
+            payload    : payload.to_string(),
+            timestamp  : None,
+            event_id   : None,
+            metadata   : String::new()
+        }
+    }
+}
+
+// An event as it's read back from a stream.  revision is its place in the stream that was read,
+// and link_id the id of the link it was read through when links are resolved, in which case the
+// rest is the event the link points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedEvent {
+    pub id          : u64,
+    pub stream_name : String,
+    pub revision    : u64,
+    pub event_type  : String,
+    pub payload     : String,
+    pub timestamp   : i64,
+    pub link_id     : Option<u64>,
+    pub metadata    : String
+}
+
+impl RecordedEvent {
+    pub fn new(revision: u64, event: Event, link_id: Option<u64>) -> Self {
+        Self {
+            id          : event.id,
+            stream_name : event.name,
+            revision,
+            event_type  : event.event_type,
+            payload     : event.payload,
+            timestamp   : event.timestamp,
+            link_id,
+            metadata    : event.metadata
+        }
+    }
+
+    // The payload as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.payload)
+    }
+}
+
 impl Event {
-    pub fn new(next_id: u64, name: &str, payload: &str) -> Result<Event, &'static str> {
+    pub fn new(next_id: u64, name: &str, event_type: &str, payload: &str) -> Result<Event, &'static str> {
         Ok(Event{
-          id        : next_id,
-          timestamp : Utc::now().timestamp(),
-          name      : name.to_string(),
-          payload   : payload.to_string()
+          id         : next_id,
+          timestamp  : Utc::now().timestamp(),
+          name       : name.to_string(),
+          payload    : payload.to_string(),
+          event_type : event_type.to_string(),
+          metadata   : String::new()
         })
     }
 }
 
+// Splits a link event payload into the revision and stream it points at.
+pub fn parse_link(payload: &str) -> Option<(u64, &str)> {
+    let (revision, stream_name) = payload.split_once('@')?;
+    Some((revision.parse().ok()?, stream_name))
+}
+
+pub fn commit_payload(event_ids: &[Option<String>]) -> String {
+    match event_ids.iter().any(Option::is_some) {
+        true  => format!("{} {}", event_ids.len(), serde_json::to_string(event_ids).unwrap()),
+        false => event_ids.len().to_string()
+    }
+}
+
+// The number of events the commit record commits and the ids they were given, if any.
+pub fn parse_commit(payload: &str) -> (usize, Vec<Option<String>>) {
+    let (count, event_ids) = payload.split_once(' ').unwrap_or((payload, "[]"));
+    (count.parse().unwrap_or(0), serde_json::from_str(event_ids).unwrap_or_default())
+}
+
 impl Clone for Event {
     fn clone(&self) -> Self {
         Self {
             id : self.id,
             timestamp: self.timestamp,
             name : self.name.clone(),
-            payload : self.payload.clone()
+            payload : self.payload.clone(),
+            event_type : self.event_type.clone(),
+            metadata : self.metadata.clone()
         }
     }
 }
\ No newline at end of file
@@ -1,7 +1,10 @@
-use std::collections::HashMap;
-use std::fs;
-use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use super::DEFAULT_EVENT_ID_WINDOW;
 use super::chunk::LogChunk;
+use super::chunk_files::ChunkFiles;
+use super::keyring::Keyring;
+use super::event::{Event, LINK_EVENT_TYPE, COMMIT_EVENT_TYPE, METADATA_EVENT_TYPE, STREAM_DELETED_EVENT_TYPE, parse_link, parse_commit};
 
 #[derive(Debug, Clone)]
 pub struct IndexElement {
@@ -9,121 +12,365 @@ pub struct IndexElement {
   pub offset : u32
 }
 
+// The time index only samples every Nth event of a chunk, keeping it small while still
+// letting time range reads seek close to where they need to start.
+const TIME_INDEX_INTERVAL: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct TimeIndexElement {
+  pub timestamp : i64,
+  pub element   : IndexElement
+}
+
+// Sparse time index of a single chunk.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkTimeIndex {
+  event_count : usize,
+  samples     : Vec<TimeIndexElement>
+}
+
+// Running totals kept per stream so stream info doesn't have to read any events.
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+  pub first_timestamp : i64,
+  pub last_timestamp  : i64,
+  pub bytes_used      : u64
+}
+
 #[derive(Clone)]
 pub struct Index {
-  map : HashMap<String, Vec<IndexElement>>
+  // Sorted, so streams can be listed a page at a time.
+  map        : BTreeMap<String, Vec<IndexElement>>,
+  // Where each link event points, keyed by the (chunk_number, offset) of the link event itself.
+  links      : HashMap<(u32, u32), IndexElement>,
+  stats      : HashMap<String, StreamStats>,
+  time_index : BTreeMap<u32, ChunkTimeIndex>,
+  // Latest timestamp of any event, the time index relies on timestamps never going back.
+  last_timestamp : i64,
+  // Ids clients gave the latest event_id_window events they appended, oldest first in the queue.
+  event_ids       : HashSet<String>,
+  event_id_order  : VecDeque<String>,
+  event_id_window : usize,
+  // Latest metadata of each stream that has any, from its $$<stream name> stream.
+  metadata   : HashMap<String, String>,
+  deleted    : HashSet<String>
 }
 
 impl Index {
   pub fn new() -> Self {
+    Self::with_event_id_window(DEFAULT_EVENT_ID_WINDOW)
+  }
+
+  pub fn with_event_id_window(event_id_window: usize) -> Self {
     println!("Creating new index/hashmap");
+
+    // $all always exists, even before anything has been written to the store.
+    let mut map = BTreeMap::new();
+    map.insert("$all".to_string(), Vec::new());
+
     Self{
-      map : HashMap::new()
+      map,
+      links      : HashMap::new(),
+      stats      : HashMap::new(),
+      time_index : BTreeMap::new(),
+      last_timestamp : 0,
+      event_ids       : HashSet::new(),
+      event_id_order  : VecDeque::new(),
+      event_id_window,
+      metadata   : HashMap::new(),
+      deleted    : HashSet::new()
     }
   }
 
-  pub fn initialize(&mut self, dir_name: &str) -> (Option<LogChunk>, u64) {
+  // Archived chunks are indexed from the summaries kept of them, and only fetched from the archive
+  // when there is none.  Only the last chunk, the one written to, has to be on local disk.
+  pub fn initialize(&mut self, chunk_files: &ChunkFiles, keyring: &Keyring) -> (Option<LogChunk>, u64) {
     let mut last_chunk : Option<LogChunk> = None;
-    let mut highest_chunk_id : u32        = 0;
     let mut next_id    : u64              = 0;
-    let re                          = Regex::new(r"chunks/(\d+)\.chk").unwrap();
-
-    // Create chunks folder if not already there...
-    fs::create_dir(dir_name).ok();
-
-    // Read all files from directory in array and sort case insensitive by filename chunk id.
-    let mut all_chunks = Vec::new();
-    for entry in fs::read_dir(dir_name).unwrap() {
-      all_chunks.push(entry);
-    }
-    all_chunks.sort_by(|a, b| {
-      let chunk_file_a = a.as_ref().unwrap();
-      let chunk_path_a   = chunk_file_a.path();
-      let chunk_path_str_a = chunk_path_a.to_str().unwrap();
-      let captures_a    = re.captures(chunk_path_str_a).unwrap();
-      let chunk_id_a        = captures_a.get(1).unwrap().as_str().parse::<u32>().unwrap();
-
-      let chunk_file_b = b.as_ref().unwrap();
-      let chunk_path_b   = chunk_file_b.path();
-      let chunk_path_str_b = chunk_path_b.to_str().unwrap();
-      let captures_b   = re.captures(chunk_path_str_b).unwrap();
-      let chunk_id_b       = captures_b.get(1).unwrap().as_str().parse::<u32>().unwrap();
-
-      chunk_id_a.cmp(&chunk_id_b)
-    });
+    let mut pending : Vec<(Event, IndexElement, u32)> = Vec::new();
+
+    let chunk_ids = match chunk_files.chunk_ids() {
+      Ok(chunk_ids) => chunk_ids,
+      Err(error) => panic!("Failed to list chunks: {}", error)
+    };
+    if let Some(last_id) = chunk_ids.last() {
+      if !chunk_files.is_local(*last_id) {
+        panic!("Chunk {} is only in the archive, the last chunk has to be on local disk", last_id);
+      }
+    }
 
     // Enumerate sorted log chunk files and load into index
-    for entry in all_chunks.iter() {
-      let chunk_file = entry.as_ref().unwrap();
-      let chunk_path  = chunk_file.path();
-      let chunk_path_str= chunk_path.to_str().unwrap();
-      let captures   = re.captures(chunk_path_str).unwrap();
-      let chunk_id       = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
+    for chunk_id in chunk_ids {
+      println!("Indexing: {:?}", chunk_id);
+
+      let is_local = chunk_files.is_local(chunk_id);
+      if !is_local {
+        if let Some(summary) = chunk_files.summary(chunk_id, keyring) {
+          let has_commit_records = summary.has_commit_records();
+          for (offset, size, event) in summary.records {
+            next_id = next_id.max(event.id+1);
+            let index_element = IndexElement{ chunk_number: chunk_id, offset };
+            self.add_committed(event, index_element, size, has_commit_records, &mut pending);
+          }
+          continue;
+        }
+      }
+
+      let contents = match chunk_files.contents(chunk_id) {
+        Ok(contents) => contents,
+        Err(error) => panic!("{}", error)
+      };
 
-      println!("Indexing: {:?} {:?}", chunk_file.path(), chunk_id);
+      // Fetched this once, the next start indexes it from its summary.
+      if !is_local {
+        if let Err(error) = chunk_files.write_summary(chunk_id, &contents, keyring) {
+          println!("Failed to write the summary of chunk {}: {}", chunk_id, error);
+        }
+      }
 
-      let (log_chunk, event_info) = LogChunk::index(chunk_id, chunk_path_str);
+      let (log_chunk, event_info) = LogChunk::index(chunk_id, contents, chunk_files.storage(), keyring);
 
-      for (i, (stream_name, _)) in event_info.iter().enumerate() {
+      for (i, (event, size)) in event_info.into_iter().enumerate() {
         let index_element = IndexElement{
           chunk_number: log_chunk.id,
           offset : *log_chunk.offsets.get(i).unwrap()
         };
-
-        self.add(stream_name, index_element);
+        next_id = next_id.max(event.id+1);
+        self.add_committed(event, index_element, size, log_chunk.has_commit_records(), &mut pending);
       }
 
-      // Files come in random order so keep track of highest chunk id so we end up knowing
-      // last chunk (write chunk) and next_id
-      if chunk_id > highest_chunk_id {
-        highest_chunk_id = chunk_id;
+      // Chunks come in order, so the last one read is the one written to.
+      last_chunk = Some(log_chunk);
+    }
 
-        last_chunk       = Some(log_chunk);
-        if event_info.len() > 0 {
-          next_id        = (event_info.get(event_info.len()-1).unwrap().1)+1;
-        } else {
-          next_id        = 1;
-        }
-      }
+    if !pending.is_empty() {
+      println!("Ignoring {} events of an append that was never committed", pending.len());
     }
     //println!("{:?}, {}", last_chunk.as_ref().unwrap().id, next_id);
     (last_chunk, next_id)
   }
 
-  pub fn add(&mut self, stream_name: &str, value: IndexElement) {
+  // Adds the event once the append it belongs to is known to be committed, keeping it in pending
+  // until then.  Chunks without commit records have every event committed.
+  fn add_committed(&mut self, event: Event, index_element: IndexElement, size: u32, has_commit_records: bool, pending: &mut Vec<(Event, IndexElement, u32)>) {
+    if !has_commit_records {
+      self.add(&event, index_element, size);
+      return;
+    }
+
+    // Events wait for the commit record of their append, which can be in a later chunk.
+    if event.event_type != COMMIT_EVENT_TYPE {
+      pending.push((event, index_element, size));
+      return;
+    }
+
+    let (count, event_ids) = parse_commit(&event.payload);
+    let committed = pending.split_off(pending.len().saturating_sub(count));
+    if !pending.is_empty() {
+      println!("Ignoring {} events of an append that was never committed", pending.len());
+      pending.clear();
+    }
+    for (event, index_element, size) in committed {
+      self.add(&event, index_element, size);
+    }
+    self.add_event_ids(event_ids.into_iter().flatten());
+  }
+
+  pub fn add(&mut self, event: &Event, value: IndexElement, size: u32) {
+    let stream_name = event.name.as_str();
     println!("Found stream {:?}", stream_name);
 
-    let value_copy = value.clone();
+    // Sample the first event of every chunk and each TIME_INDEX_INTERVAL'th after that.
+    let chunk_time_index = self.time_index.entry(value.chunk_number).or_default();
+    if chunk_time_index.event_count.is_multiple_of(TIME_INDEX_INTERVAL) {
+      chunk_time_index.samples.push(TimeIndexElement{
+        timestamp : event.timestamp,
+        element   : value.clone()
+      });
+    }
+    chunk_time_index.event_count += 1;
+    self.last_timestamp = self.last_timestamp.max(event.timestamp);
 
-    // Target specified stream first
-    if self.map.contains_key(stream_name) {
-      let vector = self.map.get_mut(stream_name).unwrap();
-      vector.push(value);
-    } else {
-      println!("Created {} stream", stream_name);
-      // First time creating a new stream
-      let value = vec![value];
-      self.map.insert(stream_name.to_string(), value);
+    if event.event_type == LINK_EVENT_TYPE {
+      match self.link_target(&event.payload) {
+        Some(target) => {
+          self.links.insert((value.chunk_number, value.offset), target);
+        }
+        None => println!("Link {} points at nothing", event.payload)
+      }
     }
 
+    if let Some(target) = stream_name.strip_prefix("$$") {
+      match event.event_type.as_str() {
+        METADATA_EVENT_TYPE => {
+          self.metadata.insert(target.to_string(), event.payload.clone());
+        }
+        STREAM_DELETED_EVENT_TYPE => {
+          self.deleted.insert(target.to_string());
+        }
+        _ => {}
+      }
+    }
+
+    // Target specified stream first
+    let new_stream = !self.map.contains_key(stream_name);
+    self.insert(stream_name, value.clone(), event.timestamp, size);
+
     // Everything also always gets put in the $all stream...
-    if self.map.contains_key("$all") {
-      let vector = self.map.get_mut("$all").unwrap();
-      vector.push(value_copy);
-    } else {
-      println!("Created $all stream");
-      let value = vec![value_copy];
-      self.map.insert("$all".to_string(), value);
+    self.insert("$all", value.clone(), event.timestamp, size);
+
+    // ...and the other system projections.  Like $all they are only index entries pointing at
+    // the original record, nothing is copied.
+    if new_stream {
+      self.insert("$streams", value.clone(), event.timestamp, size);
     }
+    if let Some(category) = Index::category(stream_name) {
+      self.insert(&format!("$ce-{}", category), value.clone(), event.timestamp, size);
+    }
+    if !event.event_type.is_empty() && !event.event_type.starts_with('$') {
+      self.insert(&format!("$et-{}", event.event_type), value, event.timestamp, size);
+    }
+  }
+
+  fn insert(&mut self, stream_name: &str, value: IndexElement, timestamp: i64, size: u32) {
+    match self.map.get_mut(stream_name) {
+      Some(vector) => vector.push(value),
+      None => {
+        // First time creating a new stream
+        println!("Created {} stream", stream_name);
+        self.map.insert(stream_name.to_string(), vec![value]);
+      }
+    }
+
+    self.update_stats(stream_name, timestamp, size);
+  }
+
+  // Streams named <category>-<id> belong to <category>, system streams don't have one.
+  fn category(stream_name: &str) -> Option<&str> {
+    if stream_name.starts_with('$') {
+      return None;
+    }
+
+    stream_name.split_once('-').map(|(category, _)| category)
+  }
+
+  fn update_stats(&mut self, stream_name: &str, timestamp: i64, size: u32) {
+    let stats = self.stats.entry(stream_name.to_string()).or_insert(StreamStats{
+      first_timestamp : timestamp,
+      last_timestamp  : timestamp,
+      bytes_used      : 0
+    });
+    stats.last_timestamp  = timestamp;
+    stats.bytes_used     += size as u64;
+  }
+
+  // Stream names in sorted order that start with prefix and sort after the given name, leaving out
+  // deleted streams.
+  pub fn stream_names<'a>(&'a self, prefix: &'a str, after: &str) -> impl Iterator<Item = &'a String> {
+    let start = match after < prefix {
+      true  => Bound::Included(prefix),
+      false => Bound::Excluded(after)
+    };
+    self.map.range::<str, _>((start, Bound::Unbounded))
+      .map(|(name, _)| name)
+      .take_while(move |name| name.starts_with(prefix))
+      .filter(|name| !self.deleted.contains(name.as_str()))
+  }
+
+  pub fn metadata(&self, stream_name: &str) -> Option<&String> {
+    self.metadata.get(stream_name)
+  }
+
+  pub fn is_deleted(&self, stream_name: &str) -> bool {
+    self.deleted.contains(stream_name)
+  }
+
+  // Stats are None only for $all while the store is still empty.
+  pub fn stream_stats(&self, stream_name: &str) -> Option<(&Vec<IndexElement>, Option<&StreamStats>)> {
+    let entries = self.map.get(stream_name)?;
+    Some((entries, self.stats.get(stream_name)))
+  }
+
+  // Find the last sampled position written strictly before timestamp.  Every event at or after
+  // timestamp is stored at or after the returned position, assuming timestamps never go backwards
+  // in write order.  None means the read has to start from the very beginning.
+  pub fn seek_time(&self, timestamp: i64) -> Option<IndexElement> {
+    let mut found : Option<IndexElement> = None;
+
+    for chunk_time_index in self.time_index.values() {
+      let first = chunk_time_index.samples.first();
+      if first.is_none() || first.unwrap().timestamp >= timestamp {
+        break;
+      }
+
+      let position = chunk_time_index.samples.partition_point(|sample| sample.timestamp < timestamp);
+      found = Some(chunk_time_index.samples[position-1].element.clone());
+    }
+
+    found
+  }
+
+  // The index entry a link event payload points at, if it exists.
+  pub fn link_target(&self, payload: &str) -> Option<IndexElement> {
+    let (revision, stream_name) = parse_link(payload)?;
+    self.map.get(stream_name)?.get(revision as usize).cloned()
+  }
+
+  // Targets of the link events among the given entries, keyed by where each link is stored.
+  pub fn links_in(&self, entries: &[IndexElement]) -> HashMap<(u32, u32), IndexElement> {
+    entries.iter()
+      .filter_map(|entry| {
+        let key = (entry.chunk_number, entry.offset);
+        self.links.get(&key).map(|target| (key, target.clone()))
+      })
+      .collect()
+  }
+
+  // Revision of the event stored at element within the stream it was written to.
+  pub fn revision(&self, stream_name: &str, element: &IndexElement) -> Option<u64> {
+    let entries = self.map.get(stream_name)?;
+    let position = entries.binary_search_by_key(
+      &(element.chunk_number, element.offset),
+      |entry| (entry.chunk_number, entry.offset)
+    ).ok()?;
+    Some(position as u64)
   }
 
-  pub fn fetch_one(&mut self, stream_name: &str) -> &Vec<IndexElement> {
-    if self.map.contains_key(stream_name) == false {
-      println!("Adding empty stream {}!", stream_name);
-      let value : Vec<IndexElement> = vec![];
-      self.map.insert(stream_name.to_string(), value);
+  pub fn last_timestamp(&self) -> i64 {
+    self.last_timestamp
+  }
+
+  // Ids beyond the window are forgotten, oldest first, so retrying an append that old writes it again.
+  pub fn add_event_ids(&mut self, event_ids: impl Iterator<Item = String>) {
+    for event_id in event_ids {
+      if self.event_ids.insert(event_id.clone()) {
+        self.event_id_order.push_back(event_id);
+      }
     }
+    while self.event_id_order.len() > self.event_id_window {
+      let event_id = self.event_id_order.pop_front().unwrap();
+      self.event_ids.remove(&event_id);
+    }
+  }
 
-    self.map.get(stream_name).unwrap()
+  pub fn has_event_id(&self, event_id: &str) -> bool {
+    self.event_ids.contains(event_id)
+  }
+
+  // Revision of the stream's last event, -1 if it doesn't exist.
+  pub fn stream_version(&self, stream_name: &str) -> i64 {
+    match self.map.get(stream_name) {
+      Some(entries) => entries.len() as i64 - 1,
+      None => -1
+    }
+  }
+
+  // Streams only come into existence with their first event, so None means there is no such stream,
+  // or that it has been deleted.
+  pub fn fetch_one(&self, stream_name: &str) -> Option<&Vec<IndexElement>> {
+    if self.deleted.contains(stream_name) {
+      return None;
+    }
+    self.map.get(stream_name)
   }
 }
\ No newline at end of file
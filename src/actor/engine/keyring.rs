@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::fs;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Bytes encryption adds to a record, the nonce in front and the tag at the end.
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_LEN + 16;
+
+// AES-256-GCM keys for encrypting records at rest, loaded from a key file with one
+// "<key id> <key as 64 hex digits>" line per key.  New chunks are encrypted with the last key in
+// the file, the others are only kept around to read chunks written before the key was rotated.
+// Key id 0 stands for no encryption.
+pub struct Keyring {
+  keys      : HashMap<u32, LessSafeKey>,
+  active_id : u32,
+  random    : SystemRandom
+}
+
+impl Keyring {
+  pub fn empty() -> Self {
+    Self {
+      keys      : HashMap::new(),
+      active_id : 0,
+      random    : SystemRandom::new()
+    }
+  }
+
+  pub fn load(path: &str) -> Result<Self, String> {
+    let contents = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let mut keyring = Keyring::empty();
+
+    // Blank lines and lines starting with # are skipped.
+    for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+      let (key_id, key) = match line.split_once(' ') {
+        Some((key_id, key)) => (key_id, key.trim()),
+        None => return Err(format!("Expected \"<key id> <key>\" but found {}", line))
+      };
+
+      let key_id = match key_id.parse::<u32>() {
+        Ok(0) | Err(_) => return Err(format!("Key id {} is not a number above 0", key_id)),
+        Ok(key_id) => key_id
+      };
+      let key = match hex::decode(key).ok().and_then(|key| UnboundKey::new(&AES_256_GCM, &key).ok()) {
+        Some(key) => key,
+        None => return Err(format!("Key {} is not 32 bytes of hex", key_id))
+      };
+
+      keyring.keys.insert(key_id, LessSafeKey::new(key));
+      keyring.active_id = key_id;
+    }
+
+    Ok(keyring)
+  }
+
+  // Key id new chunks are written with, 0 when not encrypting.
+  pub fn active_id(&self) -> u32 {
+    self.active_id
+  }
+
+  pub fn has_key(&self, key_id: u32) -> bool {
+    key_id == 0 || self.keys.contains_key(&key_id)
+  }
+
+  // Returns the nonce followed by the encrypted data and its tag.  The associated data isn't
+  // stored, the same has to be handed to open.
+  pub fn seal(&self, key_id: u32, associated_data: &[u8], data: &[u8]) -> Vec<u8> {
+    if key_id == 0 {
+      return data.to_vec();
+    }
+
+    let mut nonce = [0; NONCE_LEN];
+    self.random.fill(&mut nonce).expect("Failed to generate nonce.");
+
+    let mut sealed = data.to_vec();
+    self.keys[&key_id].seal_in_place_append_tag(
+      Nonce::assume_unique_for_key(nonce),
+      Aad::from(associated_data),
+      &mut sealed
+    ).expect("Failed to encrypt record.");
+
+    let mut record = nonce.to_vec();
+    record.append(&mut sealed);
+    record
+  }
+
+  // None if the key is missing or the data isn't what was sealed with it.
+  pub fn open(&self, key_id: u32, associated_data: &[u8], record: &[u8]) -> Option<Vec<u8>> {
+    if key_id == 0 {
+      return Some(record.to_vec());
+    }
+
+    let key   = self.keys.get(&key_id)?;
+    let nonce = Nonce::try_assume_unique_for_key(record.get(.. NONCE_LEN)?).ok()?;
+
+    let mut sealed = record[NONCE_LEN ..].to_vec();
+    let opened     = key.open_in_place(nonce, Aad::from(associated_data), &mut sealed).ok()?;
+    Some(opened.to_vec())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{SystemTime, UNIX_EPOCH};
+  use super::*;
+
+  fn load(contents: &str) -> Result<Keyring, String> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let path  = std::env::temp_dir().join(format!("betterstore-keyring-{}-{}", std::process::id(), nanos));
+    fs::write(&path, contents).unwrap();
+    let keyring = Keyring::load(path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    keyring
+  }
+
+  fn key_line(key_id: u32, byte: &str) -> String {
+    format!("{} {}\n", key_id, byte.repeat(32))
+  }
+
+  #[test]
+  fn records_only_open_with_the_key_and_associated_data_they_were_sealed_with() {
+    let keyring = load(&format!("# rotated\n{}\n{}", key_line(1, "11"), key_line(2, "22"))).unwrap();
+    assert_eq!(keyring.active_id(), 2);
+
+    let sealed = keyring.seal(2, b"offset 8", b"record");
+    assert_eq!(sealed.len(), b"record".len() + ENCRYPTION_OVERHEAD);
+    assert_eq!(keyring.open(2, b"offset 8", &sealed), Some(b"record".to_vec()));
+
+    assert_eq!(keyring.open(1, b"offset 8", &sealed), None);
+    assert_eq!(keyring.open(3, b"offset 8", &sealed), None);
+    assert_eq!(keyring.open(2, b"offset 9", &sealed), None);
+
+    let mut tampered = sealed.clone();
+    tampered[NONCE_LEN] ^= 1;
+    assert_eq!(keyring.open(2, b"offset 8", &tampered), None);
+    assert_eq!(keyring.open(2, b"offset 8", &sealed[.. NONCE_LEN - 1]), None);
+
+    // Key id 0 leaves records as they are.
+    assert_eq!(keyring.seal(0, b"offset 8", b"record"), b"record");
+    assert_eq!(keyring.open(0, b"offset 8", b"record"), Some(b"record".to_vec()));
+  }
+
+  #[test]
+  fn key_files_with_bad_lines_are_refused() {
+    assert!(load(&key_line(0, "11")).is_err());
+    assert!(load("1 1111\n").is_err());
+    assert!(load(&"11".repeat(32)).is_err());
+    assert_eq!(load("\n# no keys yet\n").unwrap().active_id(), 0);
+  }
+}
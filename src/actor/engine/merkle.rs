@@ -0,0 +1,116 @@
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+
+pub type Hash = [u8; SHA256_OUTPUT_LEN];
+
+// Merkle trees over the records of a chunk, laid out as in RFC 6962 (Certificate Transparency) so
+// proofs can be checked with any implementation of it:
+//   leaf      SHA-256(0x00 || record)
+//   node      SHA-256(0x01 || left || right)
+// A level with an odd number of nodes passes its last node up to the next level as it is.
+// The record is everything stored for it in the chunk, its length and flag included.
+
+pub fn leaf_hash(record: &[u8]) -> Hash {
+  hash_with_prefix(0, &[record])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+  hash_with_prefix(1, &[left, right])
+}
+
+fn hash_with_prefix(prefix: u8, parts: &[&[u8]]) -> Hash {
+  let mut data = vec![prefix];
+  for part in parts {
+    data.extend_from_slice(part);
+  }
+
+  let mut hash = [0; SHA256_OUTPUT_LEN];
+  hash.copy_from_slice(digest(&SHA256, &data).as_ref());
+  hash
+}
+
+// Root of the tree over leaves, all zeros for a chunk without records.
+pub fn root(leaves: &[Hash]) -> Hash {
+  if leaves.is_empty() {
+    return [0; SHA256_OUTPUT_LEN];
+  }
+
+  let mut level = leaves.to_vec();
+  while level.len() > 1 {
+    level = next_level(&level);
+  }
+  level[0]
+}
+
+// Sibling hashes from the leaf at index up to the root, each flagged with whether it's on the left.
+pub fn proof(leaves: &[Hash], mut index: usize) -> Vec<(Hash, bool)> {
+  let mut steps = Vec::new();
+
+  let mut level = leaves.to_vec();
+  while level.len() > 1 {
+    let sibling = index ^ 1;
+    if sibling < level.len() {
+      steps.push((level[sibling], sibling < index));
+    }
+
+    level  = next_level(&level);
+    index /= 2;
+  }
+
+  steps
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+  level.chunks(2).map(|pair| match pair {
+    [left, right] => node_hash(left, right),
+    [last] => *last,
+    _ => unreachable!()
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn leaves(count: usize) -> Vec<Hash> {
+    (0 .. count).map(|record| leaf_hash(&[record as u8])).collect()
+  }
+
+  // The root as RFC 6962 defines it, split at the largest power of two below the number of leaves.
+  fn rfc_6962_root(leaves: &[Hash]) -> Hash {
+    if leaves.len() == 1 {
+      return leaves[0];
+    }
+    let split = 1 << (usize::BITS - 1 - (leaves.len() - 1).leading_zeros());
+    node_hash(&rfc_6962_root(&leaves[.. split]), &rfc_6962_root(&leaves[split ..]))
+  }
+
+  fn root_from_proof(leaf: Hash, proof: &[(Hash, bool)]) -> Hash {
+    proof.iter().fold(leaf, |hash, (sibling, on_left)| match on_left {
+      true  => node_hash(sibling, &hash),
+      false => node_hash(&hash, sibling)
+    })
+  }
+
+  #[test]
+  fn every_leaf_proves_against_the_root() {
+    for count in [1, 2, 3, 4, 5, 6, 7, 8] {
+      let leaves = leaves(count);
+      let root   = root(&leaves);
+      assert_eq!(root, rfc_6962_root(&leaves), "{} leaves", count);
+
+      for index in 0 .. count {
+        let proof = proof(&leaves, index);
+        assert_eq!(root_from_proof(leaves[index], &proof), root, "leaf {} of {}", index, count);
+        assert_ne!(root_from_proof(leaf_hash(b"other"), &proof), root, "leaf {} of {}", index, count);
+      }
+    }
+  }
+
+  #[test]
+  fn proofs_skip_the_levels_a_last_odd_leaf_is_passed_up() {
+    let leaves = leaves(5);
+    assert_eq!(proof(&leaves, 4), [(root(&leaves[.. 4]), true)]);
+    assert_eq!(proof(&leaves, 2).iter().map(|(_, on_left)| *on_left).collect::<Vec<_>>(), [false, true, false]);
+    assert_eq!(root(&[]), [0; SHA256_OUTPUT_LEN]);
+  }
+}
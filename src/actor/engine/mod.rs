@@ -1,56 +1,637 @@
-use std::borrow::BorrowMut;
-use super::super::api::ReadStreamResponse;
-use tokio::sync::mpsc::Sender;
-use tonic::Status;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use futures::executor::block_on;
+use super::super::api::{ReadStreamResponse, ListStreamsResponse, GetStreamInfoResponse, VerifyResponse, BackupResponse};
+use super::super::api::{ChunkRoot, ListChunkRootsResponse, GetEventProofResponse, ProofStep};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{oneshot, watch};
 
-use index::Index;
-use writer::Writer;
+use index::{Index, IndexElement};
+use event::{Event, ProposedEvent, RecordedEvent, METADATA_EVENT_TYPE, STREAM_DELETED_EVENT_TYPE};
+use writer::{Writer, StreamAppend, AppendRequest};
+
+pub use chunk::Compression;
+pub use archive::{open_archive, Archive, LocalArchive, S3Archive, S3Config};
+pub use storage::{ChunkStorage, FileStorage, MemoryStorage};
+use chunk::LogChunk;
+use chunk_files::ChunkFiles;
 use reader::ReaderStream;
+use keyring::Keyring;
+use merkle::Hash;
+use stream_keys::StreamKeys;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+// Largest payload in bytes an event may have unless the engine is given another maximum.  Events
+// are never split across chunks, so whatever the maximum, an event has to fit in a single chunk.
+pub const DEFAULT_MAX_EVENT_SIZE: usize = 512 * 1024;
+
+// Settings of a store that can differ between deployments.
+#[derive(Clone)]
+pub struct EngineOptions {
+  // Where chunks are kept, files in ./chunks without one.
+  pub storage        : Option<Arc<dyn ChunkStorage>>,
+  pub max_event_size : usize,
+  // How newly written records are compressed, records already written keep theirs.
+  pub compression    : Compression,
+  // How many of the latest ids given to appended events are remembered to tell retries by.
+  pub event_id_window : usize,
+  // Key file to encrypt chunks with, chunks are written unencrypted without one.
+  pub key_file       : Option<String>,
+  // Directory of per-stream keys that event payloads are encrypted with so streams can be
+  // shredded, payloads aren't encrypted per stream without one.
+  pub stream_keys_dir : Option<String>,
+  // Where full chunks are moved to once there are more than keep_local_chunks of them on local
+  // disk, they're all kept locally without one.
+  pub archive           : Option<Arc<dyn Archive>>,
+  pub keep_local_chunks : usize,
+  // How often full chunks are looked for to archive.
+  pub archive_interval  : Duration,
+  // Most archived chunks kept on local disk after being read, in cache_dir.
+  pub chunk_cache_size  : usize,
+  pub cache_dir         : String,
+  // Summaries of the archived chunks, to index them without fetching them.
+  pub index_dir         : String
+}
+
+impl Default for EngineOptions {
+  fn default() -> Self {
+    Self {
+      storage        : None,
+      max_event_size : DEFAULT_MAX_EVENT_SIZE,
+      compression    : Compression::None,
+      event_id_window : DEFAULT_EVENT_ID_WINDOW,
+      key_file       : None,
+      stream_keys_dir : None,
+      archive           : None,
+      keep_local_chunks : DEFAULT_KEEP_LOCAL_CHUNKS,
+      archive_interval  : DEFAULT_ARCHIVE_INTERVAL,
+      chunk_cache_size  : DEFAULT_CHUNK_CACHE_SIZE,
+      cache_dir         : chunk_files::CACHE_DIR.to_string(),
+      index_dir         : chunk_files::INDEX_DIR.to_string()
+    }
+  }
+}
+
+pub const DEFAULT_EVENT_ID_WINDOW: usize = 100_000;
+pub const DEFAULT_KEEP_LOCAL_CHUNKS: usize = 16;
+pub const DEFAULT_CHUNK_CACHE_SIZE: usize = 16;
+pub const DEFAULT_ARCHIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Appends queued for the writer thread beyond this wait for room in the queue.
+const WRITE_QUEUE_SIZE: usize = 1024;
+
+// A stream only exists once it has events, so StreamNotFound is what tells a stream that was never
+// written to apart from reading past the end of an existing one.
+#[derive(Debug)]
+pub enum ReadError {
+  StreamNotFound(String),
+  // The stream has no event at the revision, or not one that has been flushed to disk yet.
+  RevisionNotFound(String, u64),
+  // A chunk couldn't be read, such as an archived one that couldn't be fetched.  Reading again
+  // later may work.
+  Unavailable(String)
+}
+
+impl fmt::Display for ReadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ReadError::StreamNotFound(stream_name) => write!(f, "Stream {} not found.", stream_name),
+      ReadError::RevisionNotFound(stream_name, revision) => write!(f, "Stream {} has no revision {}.", stream_name, revision),
+      ReadError::Unavailable(error) => write!(f, "{}", error)
+    }
+  }
+}
+
+impl std::error::Error for ReadError {}
+
+// Nothing of an append that failed was written, whichever the reason.
+#[derive(Debug, PartialEq)]
+pub enum AppendError {
+  // A stream wasn't at the revision the append expected.
+  WrongExpectedVersion(String),
+  // The append can't be written as given, such as to a reserved stream name or with an event too
+  // large.  Retrying it won't help.
+  Rejected(String),
+  // The writer thread has stopped, nothing more can be appended.
+  Unavailable(String),
+  // Writing failed part way, such as when a stream's key couldn't be created.
+  Failed(String)
+}
+
+impl fmt::Display for AppendError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AppendError::WrongExpectedVersion(error) | AppendError::Rejected(error) | AppendError::Unavailable(error) | AppendError::Failed(error) => write!(f, "{}", error)
+    }
+  }
+}
+
+impl std::error::Error for AppendError {}
 
 pub mod event;
+pub mod admin;
 mod chunk;
 mod index;
 mod writer;
 mod reader;
+mod keyring;
+mod stream_keys;
+mod merkle;
+mod backup;
+mod archive;
+mod chunk_files;
+mod summary;
+mod storage;
 
+// All file writes happen on a dedicated writer thread that owns the Writer, the engine only
+// queues appends for it.  The index is shared with the writer thread, which adds to it as it writes.
 pub struct Engine {
-  index   : Index,
-  writer  : Writer
+  index   : Arc<RwLock<Index>>,
+  writes  : Sender<AppendRequest>,
+  keyring : Arc<Keyring>,
+  stream_keys : Arc<StreamKeys>,
+  chunk_files : Arc<ChunkFiles>,
+  // The id the next event will get, updated by the writer thread after every flush.
+  next_id     : watch::Receiver<u64>
+}
+
+impl Default for Engine {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Engine {
   pub fn new() -> Self {
+    Self::with_options(EngineOptions::default())
+  }
+
+  // A store that only lasts as long as the engine, writing nothing to disk.
+  pub fn in_memory() -> Self {
+    Self::with_options(EngineOptions {
+      storage : Some(Arc::new(MemoryStorage::new())),
+      ..EngineOptions::default()
+    })
+  }
+
+  pub fn with_options(options: EngineOptions) -> Self {
+
+    let keyring = match &options.key_file {
+      Some(key_file) => match Keyring::load(key_file) {
+        Ok(keyring) => keyring,
+        Err(error) => panic!("Failed to load key file {}: {}", key_file, error)
+      },
+      None => Keyring::empty()
+    };
+    let keyring = Arc::new(keyring);
+
+    let stream_keys = match &options.stream_keys_dir {
+      Some(dir) => match StreamKeys::load(dir) {
+        Ok(stream_keys) => stream_keys,
+        Err(error) => panic!("Failed to load stream keys from {}: {}", dir, error)
+      },
+      None => StreamKeys::disabled()
+    };
+    let stream_keys = Arc::new(stream_keys);
+
+    let storage : Arc<dyn ChunkStorage> = match &options.storage {
+      Some(storage) => storage.clone(),
+      None => match FileStorage::new("chunks") {
+        Ok(storage) => Arc::new(storage),
+        Err(error) => panic!("Failed to open chunks directory: {}", error)
+      }
+    };
+    let chunk_files = match &options.archive {
+      Some(archive) => match ChunkFiles::with_archive(storage.clone(), archive.clone(), &options.cache_dir, &options.index_dir, options.chunk_cache_size) {
+        Ok(chunk_files) => chunk_files,
+        Err(error) => panic!("Failed to set up the chunk cache: {}", error)
+      },
+      None => ChunkFiles::new(storage.clone())
+    };
+    let chunk_files = Arc::new(chunk_files);
+
+    let mut index                       = Index::with_event_id_window(options.event_id_window);
+    let (wchunk, next_id) = index.initialize(&chunk_files, &keyring);
+
+    let writer = Writer::new(wchunk, next_id, &options, storage, keyring.clone(), stream_keys.clone());
+    let index  = Arc::new(RwLock::new(index));
 
-    let mut index                       = Index::new();
-    let (wchunk, next_id) = index.initialize("chunks");
+    let (writes, queue) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let (flushed, next_id) = watch::channel(next_id);
+    let writer_index    = index.clone();
+    thread::Builder::new()
+      .name("writer".to_string())
+      .spawn(move || writer.run(writer_index, queue, flushed))
+      .expect("Failed to start writer thread!");
 
-    let writer = Writer::new(wchunk, next_id);
+    // The archiver stops along with the engine, it only holds on to the chunk files in between.
+    if options.archive.is_some() {
+      let archiver_files    = Arc::downgrade(&chunk_files);
+      let keep_local_chunks = options.keep_local_chunks;
+      let archive_interval  = options.archive_interval;
+      let archiver_keyring  = keyring.clone();
+      thread::Builder::new()
+        .name("archiver".to_string())
+        .spawn(move || loop {
+          thread::sleep(archive_interval);
+          let chunk_files = match archiver_files.upgrade() {
+            Some(chunk_files) => chunk_files,
+            None => return
+          };
+          if let Err(error) = chunk_files.archive_sealed(keep_local_chunks, &archiver_keyring) {
+            println!("Failed to archive chunks: {}", error);
+          }
+        })
+        .expect("Failed to start archiver thread!");
+    }
 
     Self {
       index,
-      writer
+      writes,
+      keyring,
+      stream_keys,
+      chunk_files,
+      next_id
     }
   }
 
-  pub fn append_events(&mut self, stream_name: String, events: Vec<String>) -> Result<u64, String> {
-    // You can't append events to certain "reserved" stream names.
-    match stream_name.as_str() {
-      "$all" => Err(format!("Illegal stream_name parameter.")),
-      _ => {
-        // Returns next_id
-        Ok(self.writer.append_events(
-          self.index.borrow_mut(),
-          stream_name,
-          events
-        ))
+  // Changes whenever appended events have been flushed, to the id the next event will get.
+  pub fn watch_appends(&self) -> watch::Receiver<u64> {
+    self.next_id.clone()
+  }
+
+  // Appends without checking for reserved names, for the streams the server keeps for itself.
+  // Blocks until the events are written, so it's only for threads that may block.
+  pub fn append_system_events(&self, stream_name: String, events: Vec<ProposedEvent>) -> Result<(), String> {
+    self.append_system_streams(vec![(stream_name, events)])
+  }
+
+  // Appends to several streams in one transaction, reserved names included.  Blocks like
+  // append_system_events.
+  pub fn append_system_streams(&self, streams: Vec<(String, Vec<ProposedEvent>)>) -> Result<(), String> {
+    let streams = streams.into_iter().map(|(stream_name, events)| {
+      StreamAppend{ stream_name, events, expected_version: None }
+    }).collect();
+    block_on(self.queue_append(streams, true)).map(|_| ()).map_err(|error| error.to_string())
+  }
+
+  // Appends to several streams in one transaction, all of the events are written or none are.
+  pub fn append_to_streams(&self, streams: Vec<(String, Vec<ProposedEvent>)>) -> impl Future<Output = Result<(), AppendError>> {
+    let streams = streams.into_iter().map(|(stream_name, events)| {
+      StreamAppend{ stream_name, events, expected_version: None }
+    }).collect();
+
+    let append = self.queue_append(streams, false);
+    async move { append.await.map(|_| ()) }
+  }
+
+  // Appends only if the stream's last revision is expected_version (-1 for a stream that doesn't
+  // exist yet), resolving to the stream's last revision after the append.
+  pub fn append_expecting(&self, stream_name: String, events: Vec<ProposedEvent>, expected_version: Option<i64>) -> impl Future<Output = Result<i64, AppendError>> {
+    let streams = vec![StreamAppend{ stream_name, events, expected_version }];
+
+    let append = self.queue_append(streams, false);
+    async move { append.await.map(|versions| versions[0]) }
+  }
+
+  // Appends only if the stream's last revision is expected_version, like append_expecting, but
+  // resolves as soon as the append is in the writer thread's queue, to a future that resolves once
+  // it's written.  Queued appends are written in the order they were queued, so the caller can keep
+  // several of them waiting on the writer, which then syncs them together.
+  pub fn queue_expecting(&self, stream_name: String, events: Vec<ProposedEvent>, expected_version: Option<i64>) -> impl Future<Output = Result<impl Future<Output = Result<i64, AppendError>>, AppendError>> {
+    let streams = vec![StreamAppend{ stream_name, events, expected_version }];
+
+    let queued = self.enqueue(streams, false);
+    async move {
+      let append = queued.await?;
+      Ok(async move { append.await.map(|versions| versions[0]) })
+    }
+  }
+
+  // The returned future resolves once the writer thread has written and synced the append.
+  fn queue_append(&self, streams: Vec<StreamAppend>, system: bool) -> impl Future<Output = Result<Vec<i64>, AppendError>> {
+    let queued = self.enqueue(streams, system);
+    async move { queued.await?.await }
+  }
+
+  fn enqueue(&self, streams: Vec<StreamAppend>, system: bool) -> impl Future<Output = Result<impl Future<Output = Result<Vec<i64>, AppendError>>, AppendError>> {
+    let writes = self.writes.clone();
+
+    async move {
+      let (reply, response) = oneshot::channel();
+      if writes.send(AppendRequest{ streams, system, reply }).await.is_err() {
+        return Err(AppendError::Unavailable("Writer thread has stopped.".to_string()));
       }
+
+      Ok(async move {
+        match response.await {
+          Ok(result) => result,
+          Err(_) => Err(AppendError::Unavailable("Writer thread has stopped.".to_string()))
+        }
+      })
     }
   }
 
-  pub async fn read_stream(&mut self, stream_name: String, stream_position: u64, tx_channel: Sender<Result<ReadStreamResponse, Status>>) {
-    let mut reader = ReaderStream::new(stream_name, &mut self.index);
+  // Snapshots what the read needs from the index up front, so the returned future can be driven
+  // without keeping the engine borrowed (or locked) while events are sent out.
+  // With resolve_links, link events are read as the event they point at.
+  pub fn read_stream(&self, stream_name: String, stream_position: u64, start_time: i64, end_time: i64, resolve_links: bool, tx_channel: Sender<Result<RecordedEvent, ReadError>>) -> Result<impl Future<Output = ()>, ReadError> {
+    let index = self.index.read().unwrap();
+    let index_entries = match index.fetch_one(&stream_name) {
+      Some(entries) => entries.clone(),
+      None => return Err(ReadError::StreamNotFound(stream_name))
+    };
+    let time_seek = match start_time {
+      0 => None,
+      _ => index.seek_time(start_time)
+    };
+    let mut reader = match resolve_links {
+      true => {
+        let start = (stream_position as usize).min(index_entries.len());
+        let links = index.links_in(&index_entries[start..]);
+        ReaderStream::with_links(index_entries, links, self.keyring.clone(), self.stream_keys.clone(), self.chunk_files.clone())
+      }
+      false => ReaderStream::new(index_entries, self.keyring.clone(), self.stream_keys.clone(), self.chunk_files.clone())
+    };
 
-    reader.read_stream(tx_channel, stream_position).await;
+    Ok(async move {
+      reader.read_stream(tx_channel, stream_position, start_time, end_time, time_seek).await;
+    })
   }
 
-}
\ No newline at end of file
+  // Reads up to max_count events from stream_position on, for use inside the server.  Each event
+  // comes with its revision in the stream it was written to.
+  pub fn read_events(&self, stream_name: &str, stream_position: u64, max_count: usize) -> Result<Vec<(u64, Event)>, ReadError> {
+    // Only the entries that will actually be read need to be handed to the reader.
+    let entries = match self.index.read().unwrap().fetch_one(stream_name) {
+      Some(entries) => {
+        let start = (stream_position as usize).min(entries.len());
+        let end   = start.saturating_add(max_count).min(entries.len());
+        entries[start..end].to_vec()
+      }
+      None => return Err(ReadError::StreamNotFound(stream_name.to_string()))
+    };
+
+    // The index isn't locked while reading, so the writer thread doesn't have to wait on it.
+    let events = ReaderStream::new(entries, self.keyring.clone(), self.stream_keys.clone(), self.chunk_files.clone())
+      .read_events(0, max_count)
+      .map_err(ReadError::Unavailable)?;
+
+    let index = self.index.read().unwrap();
+    Ok(events.into_iter().map(|(element, event)| {
+      let revision = index.revision(&event.name, &element).unwrap();
+      (revision, event)
+    }).collect())
+  }
+
+  // Records the stream's metadata, a JSON document, in place of whatever it had before.  The
+  // stream doesn't have to exist.
+  pub fn set_stream_metadata(&self, stream_name: &str, metadata: &str) -> impl Future<Output = Result<(), AppendError>> {
+    let checked = match serde_json::from_str::<serde_json::Value>(metadata) {
+      Ok(_) if stream_name.is_empty() || stream_name.starts_with('$') => Err(AppendError::Rejected("Illegal stream_name parameter.".to_string())),
+      Ok(_) => Ok(()),
+      Err(error) => Err(AppendError::Rejected(format!("Metadata isn't JSON: {}", error)))
+    };
+    self.append_to_metadata_stream(stream_name, METADATA_EVENT_TYPE, metadata, checked)
+  }
+
+  // Deleted streams read as if they never existed and can't be appended to, while their events
+  // stay in $all and the other system projections.
+  pub fn delete_stream(&self, stream_name: &str) -> impl Future<Output = Result<(), AppendError>> {
+    let checked = match self.index.read().unwrap().fetch_one(stream_name) {
+      Some(_) if !stream_name.starts_with('$') => Ok(()),
+      Some(_) => Err(AppendError::Rejected("Illegal stream_name parameter.".to_string())),
+      None => Err(AppendError::Rejected(format!("Stream {} not found.", stream_name)))
+    };
+    self.append_to_metadata_stream(stream_name, STREAM_DELETED_EVENT_TYPE, "{}", checked)
+  }
+
+  fn append_to_metadata_stream(&self, stream_name: &str, event_type: &str, payload: &str, checked: Result<(), AppendError>) -> impl Future<Output = Result<(), AppendError>> {
+    let streams = vec![StreamAppend{
+      stream_name      : format!("$${}", stream_name),
+      events           : vec![ProposedEvent::new(event_type, payload)],
+      expected_version : None
+    }];
+    let append = checked.map(|()| self.queue_append(streams, true));
+
+    async move { append?.await.map(|_| ()) }
+  }
+
+  // Destroys the stream's key, leaving its encrypted payloads unreadable for good.  The stream can't
+  // be appended to anymore.
+  pub fn shred_stream(&self, stream_name: &str) -> Result<(), String> {
+    self.stream_keys.shred(stream_name)
+  }
+
+  // Checks the chunks from the first on, stopping at the first one that fails.  Only reads files, so
+  // it can run alongside the writer thread, whose last chunk may have records it hasn't flushed yet.
+  // Archived chunks are fetched to be checked like the others.
+  pub fn verify(&self) -> impl Future<Output = Result<VerifyResponse, String>> {
+    let chunk_files = self.chunk_files.clone();
+
+    async move {
+      tokio::task::spawn_blocking(move || Engine::verify_chunks(&chunk_files)).await.map_err(|error| error.to_string())
+    }
+  }
+
+  fn verify_chunks(chunk_files: &ChunkFiles) -> VerifyResponse {
+    let mut response = VerifyResponse {
+      ok              : true,
+      chunks_verified : 0,
+      broken_chunk    : 0,
+      error           : String::new(),
+      head_hash       : String::new()
+    };
+
+    let chunk_ids = match chunk_files.chunk_ids() {
+      Ok(chunk_ids) => chunk_ids,
+      Err(error) => {
+        response.ok    = false;
+        response.error = error;
+        return response;
+      }
+    };
+
+    let mut previous_hash = [0; 32];
+    for (i, chunk_id) in chunk_ids.iter().enumerate() {
+      // A missing chunk would otherwise go unnoticed, the chunk after it has no way of knowing.
+      let result = match *chunk_id == i as u32 + 1 {
+        true  => chunk_files.read(*chunk_id, |contents| LogChunk::verify(contents, &previous_hash, i == chunk_ids.len() - 1)),
+        false => Err(format!("Chunk {} is missing", i + 1))
+      };
+
+      match result {
+        Ok(hash) => {
+          previous_hash = hash;
+          response.chunks_verified += 1;
+        }
+        Err(error) => {
+          response.ok           = false;
+          response.broken_chunk = i as u32 + 1;
+          response.error        = error;
+          return response;
+        }
+      }
+    }
+
+    response.head_hash = hex::encode(previous_hash);
+    response
+  }
+
+  // Copies the store to target_dir, which has to be empty, while appends carry on.  With since_dir
+  // only what changed since that backup is copied.  Resolves once the copy is on disk.
+  pub fn backup(&self, target_dir: String, since_dir: Option<String>) -> impl Future<Output = Result<BackupResponse, String>> {
+    let stream_keys = self.stream_keys.clone();
+    let chunk_files = self.chunk_files.clone();
+    let keyring     = self.keyring.clone();
+
+    async move {
+      let backup = move || backup::backup(&chunk_files, &keyring, &target_dir, since_dir.as_deref(), &stream_keys);
+      match tokio::task::spawn_blocking(backup).await {
+        Ok(result) => result,
+        Err(error) => Err(error.to_string())
+      }
+    }
+  }
+
+  // Chunks are read straight from disk, like verify, without waiting on the writer thread.
+  pub fn list_chunk_roots(&self) -> impl Future<Output = Result<ListChunkRootsResponse, String>> {
+    let chunk_files = self.chunk_files.clone();
+
+    async move {
+      match tokio::task::spawn_blocking(move || Engine::chunk_roots(&chunk_files)).await {
+        Ok(result) => result,
+        Err(error) => Err(error.to_string())
+      }
+    }
+  }
+
+  fn chunk_roots(chunk_files: &ChunkFiles) -> Result<ListChunkRootsResponse, String> {
+    let mut roots = Vec::new();
+    for chunk_number in chunk_files.chunk_ids()? {
+      let leaves : Vec<Hash> = chunk_files.read(chunk_number, LogChunk::record_leaves)?
+        .into_iter().map(|(_, leaf)| leaf).collect();
+
+      roots.push(ChunkRoot {
+        chunk_number,
+        record_count : leaves.len() as u32,
+        root         : hex::encode(merkle::root(&leaves))
+      });
+    }
+
+    Ok(ListChunkRootsResponse { roots })
+  }
+
+  // The chunk is read on a blocking thread, like verify's.
+  pub fn event_proof(&self, stream_name: &str, revision: u64) -> impl Future<Output = Result<GetEventProofResponse, ReadError>> {
+    let element = match self.index.read().unwrap().fetch_one(stream_name) {
+      Some(entries) => entries.get(revision as usize).cloned().ok_or(ReadError::RevisionNotFound(stream_name.to_string(), revision)),
+      None => Err(ReadError::StreamNotFound(stream_name.to_string()))
+    };
+    let reader      = ReaderStream::new(element.iter().cloned().collect(), self.keyring.clone(), self.stream_keys.clone(), self.chunk_files.clone());
+    let chunk_files = self.chunk_files.clone();
+    let stream_name = stream_name.to_string();
+
+    async move {
+      let element = element?;
+      let prove   = move || Engine::prove(&chunk_files, reader, element, stream_name, revision);
+      tokio::task::spawn_blocking(prove).await.unwrap_or_else(|error| Err(ReadError::Unavailable(error.to_string())))
+    }
+  }
+
+  fn prove(chunk_files: &ChunkFiles, reader: ReaderStream, element: IndexElement, stream_name: String, revision: u64) -> Result<GetEventProofResponse, ReadError> {
+    let chunk_number = element.chunk_number;
+    let records = chunk_files.read(chunk_number, LogChunk::record_leaves)
+      .map_err(|error| ReadError::Unavailable(format!("Problem reading chunk {}: {}", chunk_number, error)))?;
+    let leaves : Vec<Hash> = records.iter().map(|(_, leaf)| *leaf).collect();
+
+    // The index is added to before the chunk is flushed, so the record may not be hashed yet.
+    let record_index = match records.binary_search_by_key(&element.offset, |(offset, _)| *offset) {
+      Ok(record_index) => record_index,
+      Err(_) => return Err(ReadError::RevisionNotFound(stream_name, revision))
+    };
+    let record = chunk_files.read(chunk_number, |contents| LogChunk::read_raw_record(contents, element.offset))
+      .map_err(|error| ReadError::Unavailable(format!("Problem reading chunk {}: {}", chunk_number, error)))?;
+
+    let (_, event) = reader
+      .read_events(0, 1)
+      .map_err(ReadError::Unavailable)?
+      .remove(0);
+    let event = ReadStreamResponse {
+      event           : event.payload,
+      stream_position : event.id,
+      timestamp       : event.timestamp,
+      event_type      : event.event_type,
+      stream_name     : event.name,
+      link_id         : None,
+      revision,
+      metadata        : event.metadata
+    };
+
+    let proof = merkle::proof(&leaves, record_index).into_iter()
+      .map(|(hash, left)| ProofStep { hash: hex::encode(hash), left })
+      .collect();
+
+    Ok(GetEventProofResponse {
+      event        : Some(event),
+      chunk_number : element.chunk_number,
+      record,
+      record_index : record_index as u32,
+      record_count : leaves.len() as u32,
+      proof,
+      root         : hex::encode(merkle::root(&leaves))
+    })
+  }
+
+  pub fn list_streams(&self, prefix: &str, page_token: &str, page_size: u32) -> ListStreamsResponse {
+    let page_size = match page_size {
+      0 => DEFAULT_PAGE_SIZE,
+      _ => page_size as usize
+    };
+
+    // The page token is simply the last stream name of the previous page.
+    let index = self.index.read().unwrap();
+    let mut names = index.stream_names(prefix, page_token);
+    let stream_names : Vec<String> = names.by_ref().take(page_size).map(|name| name.to_string()).collect();
+
+    let next_page_token = match names.next().is_some() {
+      true  => stream_names.last().unwrap().clone(),
+      false => String::new()
+    };
+
+    ListStreamsResponse {
+      stream_names,
+      next_page_token
+    }
+  }
+
+  pub fn get_stream_info(&self, stream_name: &str) -> Result<GetStreamInfoResponse, ReadError> {
+    let index = self.index.read().unwrap();
+    let (entries, stats) = match index.stream_stats(stream_name) {
+      Some(found) => found,
+      None => return Err(ReadError::StreamNotFound(stream_name.to_string()))
+    };
+
+    let (first_timestamp, last_timestamp, bytes_used) = match stats {
+      Some(stats) => (stats.first_timestamp, stats.last_timestamp, stats.bytes_used),
+      None => (0, 0, 0)
+    };
+
+    Ok(GetStreamInfoResponse {
+      stream_name     : stream_name.to_string(),
+      event_count     : entries.len() as u64,
+      first_revision  : 0,
+      last_revision   : entries.len().saturating_sub(1) as u64,
+      first_timestamp,
+      last_timestamp,
+      bytes_used,
+      shredded        : self.stream_keys.is_shredded(stream_name),
+      metadata        : index.metadata(stream_name).cloned().unwrap_or_default(),
+      deleted         : index.is_deleted(stream_name)
+    })
+  }
+}
@@ -1,30 +1,58 @@
 
-use super::index::{Index, IndexElement};
-use super::super::super::api::ReadStreamResponse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use super::index::IndexElement;
 use tokio::sync::mpsc::Sender;
-use tonic::Status;
 
 use super::chunk::LogChunk;
+use super::chunk_files::ChunkFiles;
+use super::event::{Event, RecordedEvent};
+use super::keyring::Keyring;
+use super::stream_keys::StreamKeys;
+use super::ReadError;
 
 
-pub struct ReaderStream<'stream> {
-  stream_name   : String,
-  index_entries : &'stream Vec<IndexElement>
+// Owns a snapshot of the stream's index entries so reading never has to hold on to the Index.
+// Clones share the snapshot.
+#[derive(Clone)]
+pub struct ReaderStream {
+  index_entries : Arc<Vec<IndexElement>>,
+  // Targets of the link events among index_entries, keyed by (chunk_number, offset) of the link.
+  // Only filled in when links are to be resolved.
+  links         : Arc<HashMap<(u32, u32), IndexElement>>,
+  keyring       : Arc<Keyring>,
+  stream_keys   : Arc<StreamKeys>,
+  chunk_files   : Arc<ChunkFiles>
 }
 
-impl <'stream> ReaderStream<'stream> {
-  pub fn new(stream_name: String, index: &'stream mut Index) -> Self {
-    let index_entries = index.fetch_one(&stream_name);
+impl ReaderStream {
+  pub fn new(index_entries: Vec<IndexElement>, keyring: Arc<Keyring>, stream_keys: Arc<StreamKeys>, chunk_files: Arc<ChunkFiles>) -> Self {
+    Self {
+      index_entries : Arc::new(index_entries),
+      links         : Arc::new(HashMap::new()),
+      keyring,
+      stream_keys,
+      chunk_files
+    }
+  }
 
+  pub fn with_links(index_entries: Vec<IndexElement>, links: HashMap<(u32, u32), IndexElement>, keyring: Arc<Keyring>, stream_keys: Arc<StreamKeys>, chunk_files: Arc<ChunkFiles>) -> Self {
     Self {
-      stream_name,
-      index_entries
+      index_entries : Arc::new(index_entries),
+      links         : Arc::new(links),
+      keyring,
+      stream_keys,
+      chunk_files
     }
   }
 
-  pub async fn read_stream(&mut self, tx_channel: Sender<Result<ReadStreamResponse, Status>>, start : u64) {
+  // start_time and end_time are unix timestamps in seconds, inclusive, 0 leaves that end unbounded.
+  // time_seek is where the time index says events at or after start_time begin, if known.
+  // A chunk that can't be read ends the read with an error.  Chunks are read on Tokio's blocking
+  // threads, fetching an archived one can take a while.
+  pub async fn read_stream(&mut self, tx_channel: Sender<Result<RecordedEvent, ReadError>>, start : u64, start_time: i64, end_time: i64, time_seek: Option<IndexElement>) {
 
-    if self.index_entries.len() == 0 {
+    if self.index_entries.is_empty() {
       return;
     }
 
@@ -33,43 +61,130 @@ impl <'stream> ReaderStream<'stream> {
     }
 
     let mut current = start as usize;
-    loop {
-      let index_element = &self.index_entries[current];
-      let chunk_path_str = format!("chunks/{}.chk", index_element.chunk_number);
-      println!("Reading from offset {}", index_element.offset);
-
-      let chunk_events = LogChunk::stream_events_out(index_element.offset, chunk_path_str.as_str());
-      let chunk_events = match chunk_events {
-        Ok(ce) => ce,
-        Err(error) => panic!("Problem opening chunk file: {:?}", error),
-      };
 
-      if self.stream_name == "$all" {
-        for event in chunk_events.iter() {
-          let response = ReadStreamResponse {
-            event : event.payload.clone(),
-            stream_position : event.id
-          };
+    // Skip everything the time index says was written before start_time.
+    if let Some(seek) = time_seek {
+      let first = self.index_entries.partition_point(|entry|
+        (entry.chunk_number, entry.offset) < (seek.chunk_number, seek.offset)
+      );
+      current = current.max(first);
+    }
+
+    while current < self.index_entries.len() {
+      let first  = current as u64;
+      let reader = self.clone();
+      let read   = tokio::task::spawn_blocking(move || {
+        let events  = reader.next_chunk(&mut current)?;
+        let targets = reader.resolve_links(&events)?;
+        Ok((events, targets, current))
+      }).await.unwrap_or_else(|error| Err(error.to_string()));
+
+      let (events, targets) = match read {
+        Ok((events, targets, next)) => {
+          current = next;
+          (events, targets)
+        }
+        Err(error) => {
+          let _ = tx_channel.send(Err(ReadError::Unavailable(error))).await;
+          return;
+        }
+      };
 
-          // TODO: Handle send errors
-          let _ = tx_channel.send(Ok(response)).await;
-          current += 1;
+      for (revision, ((_, event), target)) in (first..).zip(events.into_iter().zip(targets)) {
+        // Time ranges apply to when the stream's own event was written, even for links.
+        if start_time > 0 && event.timestamp < start_time {
+          continue;
         }
-      } else {
-        for event in chunk_events.iter().filter(|evn| evn.name == self.stream_name) {
-          let response = ReadStreamResponse {
-            event : event.payload.clone(),
-            stream_position: event.id
-          };
-
-          // TODO: Handle send errors
-          let _ = tx_channel.send(Ok(response)).await;
-          current += 1;
+        if end_time > 0 && event.timestamp > end_time {
+          return;
+        }
+
+        let event = match target {
+          Some(target) => RecordedEvent::new(revision, target, Some(event.id)),
+          None => RecordedEvent::new(revision, event, None)
+        };
+
+        // Stop reading once the receiving side has gone away.
+        if tx_channel.send(Ok(event)).await.is_err() {
+          return;
         }
       }
-      if current > self.index_entries.len()-1 {
-        break;
+    }
+  }
+
+  // Reads up to max_count events starting at stream position start, for callers inside the server
+  // that want the events themselves rather than having them sent out to a client.
+  pub fn read_events(&self, start: u64, max_count: usize) -> Result<Vec<(IndexElement, Event)>, String> {
+    let mut current = start as usize;
+    let mut events  = Vec::new();
+
+    while current < self.index_entries.len() && events.len() < max_count {
+      events.append(&mut self.next_chunk(&mut current)?);
+    }
+    events.truncate(max_count);
+
+    Ok(events)
+  }
+
+  // Reads this stream's events from the chunk holding index entry current, leaving current at the
+  // first entry in a later chunk.
+  fn next_chunk(&self, current: &mut usize) -> Result<Vec<(IndexElement, Event)>, String> {
+    let chunk_number = self.index_entries[*current].chunk_number;
+
+    let mut end = *current;
+    while end < self.index_entries.len() && self.index_entries[end].chunk_number == chunk_number {
+      end += 1;
+    }
+
+    let elements = &self.index_entries[*current..end];
+    *current = end;
+
+    let events = self.read_chunk(chunk_number, elements)?;
+    Ok(elements.iter().cloned().zip(events).collect())
+  }
+
+  // Looks up the event each link event points at, None for events that aren't links.
+  fn resolve_links(&self, events: &[(IndexElement, Event)]) -> Result<Vec<Option<Event>>, String> {
+    let targets : Vec<Option<&IndexElement>> = events.iter()
+      .map(|(element, _)| self.links.get(&(element.chunk_number, element.offset)))
+      .collect();
+
+    // Targets can be anywhere in the log, so group them by chunk to read each chunk only once.
+    let mut by_chunk : BTreeMap<u32, Vec<IndexElement>> = BTreeMap::new();
+    for target in targets.iter().flatten() {
+      by_chunk.entry(target.chunk_number).or_default().push((*target).clone());
+    }
+
+    let mut resolved : HashMap<(u32, u32), Event> = HashMap::new();
+    for (chunk_number, elements) in by_chunk {
+      let events = self.read_chunk(chunk_number, &elements)?;
+      for (element, event) in elements.iter().zip(events) {
+        resolved.insert((element.chunk_number, element.offset), event);
       }
     }
+
+    Ok(targets.iter()
+      .map(|target| target.map(|target| resolved[&(target.chunk_number, target.offset)].clone()))
+      .collect())
   }
-}
\ No newline at end of file
+
+  // Reads the events at the given offsets of one chunk, in the order the elements are given.  An
+  // archived chunk is fetched back first, which can fail like reading any chunk can.
+  fn read_chunk(&self, chunk_number: u32, elements: &[IndexElement]) -> Result<Vec<Event>, String> {
+    let first_offset   = elements.iter().map(|element| element.offset).min().unwrap();
+    println!("Reading from offset {}", first_offset);
+
+    let chunk_events = self.chunk_files.read(chunk_number, |contents| LogChunk::stream_events_out(first_offset, contents, &self.keyring))
+      .map_err(|error| format!("Problem reading chunk {}: {}", chunk_number, error))?;
+
+    // Chunk events are ordered by offset, so each element can be found with a binary search.
+    elements.iter().map(|element| {
+      let mut event = match chunk_events.binary_search_by_key(&element.offset, |(offset, _)| *offset) {
+        Ok(i) => chunk_events[i].1.clone(),
+        Err(_) => return Err(format!("Index entry {} not found in chunk {}", element.offset, chunk_number))
+      };
+      self.stream_keys.open(&mut event);
+      Ok(event)
+    }).collect()
+  }
+}
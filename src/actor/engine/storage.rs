@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::prelude::FileExt;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use super::chunk::LogChunk;
+
+// Where the contents of chunks are kept.  Chunks are only ever appended to, apart from the hash at
+// the start of their header which is rewritten on every flush, and cut short when recovering from
+// torn writes.  Only the writer thread writes, reads can happen alongside it from any thread.
+pub trait ChunkStorage: Send + Sync {
+  // Creates an empty chunk, failing if there already is one.
+  fn create(&self, chunk_number: u32) -> Result<(), String>;
+
+  fn append(&self, chunk_number: u32, data: &[u8]) -> Result<(), String>;
+
+  fn write_at(&self, chunk_number: u32, offset: u64, data: &[u8]) -> Result<(), String>;
+
+  fn read(&self, chunk_number: u32) -> Result<Vec<u8>, String>;
+
+  fn truncate(&self, chunk_number: u32, length: u64) -> Result<(), String>;
+
+  // Returns once everything written to the chunk so far survives a crash.
+  fn sync(&self, chunk_number: u32) -> Result<(), String>;
+
+  // Chunk numbers of every chunk, in order.
+  fn list(&self) -> Result<Vec<u32>, String>;
+
+  fn remove(&self, chunk_number: u32) -> Result<(), String>;
+
+  fn exists(&self, chunk_number: u32) -> bool;
+}
+
+// Chunks as <n>.chk files in a directory, the way the server keeps them.
+pub struct FileStorage {
+  dir  : String,
+  // The chunk last written to and its length, kept open as the writer keeps writing to it.
+  open : Mutex<Option<(u32, File, u64)>>
+}
+
+impl FileStorage {
+  // Creates dir if it isn't there yet.
+  pub fn new(dir: &str) -> Result<Self, String> {
+    fs::create_dir_all(dir).map_err(|error| format!("{}: {}", dir, error))?;
+    Ok(Self {
+      dir  : dir.to_string(),
+      open : Mutex::new(None)
+    })
+  }
+
+  fn path(&self, chunk_number: u32) -> String {
+    format!("{}/{}.chk", self.dir, chunk_number)
+  }
+
+  // Runs write on the chunk's file and its length, opening it unless it's the one already open.
+  fn with_file<T>(&self, chunk_number: u32, write: impl FnOnce(&File, &mut u64) -> io::Result<T>) -> Result<T, String> {
+    let mut open = self.open.lock().unwrap();
+    if !matches!(*open, Some((open_chunk, _, _)) if open_chunk == chunk_number) {
+      // Not opened in append mode, as that would make the positional hash write at offset 0 append instead.
+      let path   = self.path(chunk_number);
+      let file   = OpenOptions::new().read(true).write(true).open(&path).map_err(|error| format!("{}: {}", path, error))?;
+      let length = file.metadata().map_err(|error| error.to_string())?.len();
+      *open = Some((chunk_number, file, length));
+    }
+
+    let (_, file, length) = open.as_mut().unwrap();
+    write(file, length).map_err(|error| error.to_string())
+  }
+}
+
+impl ChunkStorage for FileStorage {
+  fn create(&self, chunk_number: u32) -> Result<(), String> {
+    let path = self.path(chunk_number);
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create_new(true)
+      .open(&path).map_err(|error| format!("{}: {}", path, error))?;
+
+    *self.open.lock().unwrap() = Some((chunk_number, file, 0));
+    Ok(())
+  }
+
+  fn append(&self, chunk_number: u32, data: &[u8]) -> Result<(), String> {
+    self.with_file(chunk_number, |file, length| {
+      file.write_all_at(data, *length)?;
+      *length += data.len() as u64;
+      Ok(())
+    })
+  }
+
+  fn write_at(&self, chunk_number: u32, offset: u64, data: &[u8]) -> Result<(), String> {
+    self.with_file(chunk_number, |file, length| {
+      file.write_all_at(data, offset)?;
+      *length = (*length).max(offset + data.len() as u64);
+      Ok(())
+    })
+  }
+
+  fn read(&self, chunk_number: u32) -> Result<Vec<u8>, String> {
+    let path = self.path(chunk_number);
+    fs::read(&path).map_err(|error| format!("{}: {}", path, error))
+  }
+
+  fn truncate(&self, chunk_number: u32, new_length: u64) -> Result<(), String> {
+    self.with_file(chunk_number, |file, length| {
+      file.set_len(new_length)?;
+      *length = new_length;
+      Ok(())
+    })
+  }
+
+  fn sync(&self, chunk_number: u32) -> Result<(), String> {
+    self.with_file(chunk_number, |file, _| file.sync_data())
+  }
+
+  fn list(&self) -> Result<Vec<u32>, String> {
+    LogChunk::chunk_ids(&self.dir)
+  }
+
+  fn remove(&self, chunk_number: u32) -> Result<(), String> {
+    let mut open = self.open.lock().unwrap();
+    if matches!(*open, Some((open_chunk, _, _)) if open_chunk == chunk_number) {
+      *open = None;
+    }
+
+    let path = self.path(chunk_number);
+    fs::remove_file(&path).map_err(|error| format!("{}: {}", path, error))
+  }
+
+  fn exists(&self, chunk_number: u32) -> bool {
+    Path::new(&self.path(chunk_number)).exists()
+  }
+}
+
+// Chunks kept in memory only, for stores that don't need to outlive the process, such as in tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+  chunks : RwLock<BTreeMap<u32, Vec<u8>>>
+}
+
+impl MemoryStorage {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn with_chunk<T>(&self, chunk_number: u32, write: impl FnOnce(&mut Vec<u8>) -> T) -> Result<T, String> {
+    match self.chunks.write().unwrap().get_mut(&chunk_number) {
+      Some(contents) => Ok(write(contents)),
+      None => Err(format!("Chunk {} does not exist", chunk_number))
+    }
+  }
+}
+
+impl ChunkStorage for MemoryStorage {
+  fn create(&self, chunk_number: u32) -> Result<(), String> {
+    let mut chunks = self.chunks.write().unwrap();
+    if chunks.contains_key(&chunk_number) {
+      return Err(format!("Chunk {} already exists", chunk_number));
+    }
+    chunks.insert(chunk_number, Vec::new());
+    Ok(())
+  }
+
+  fn append(&self, chunk_number: u32, data: &[u8]) -> Result<(), String> {
+    self.with_chunk(chunk_number, |contents| contents.extend_from_slice(data))
+  }
+
+  fn write_at(&self, chunk_number: u32, offset: u64, data: &[u8]) -> Result<(), String> {
+    self.with_chunk(chunk_number, |contents| {
+      let offset = offset as usize;
+      if contents.len() < offset + data.len() {
+        contents.resize(offset + data.len(), 0);
+      }
+      contents[offset .. offset + data.len()].copy_from_slice(data);
+    })
+  }
+
+  fn read(&self, chunk_number: u32) -> Result<Vec<u8>, String> {
+    match self.chunks.read().unwrap().get(&chunk_number) {
+      Some(contents) => Ok(contents.clone()),
+      None => Err(format!("Chunk {} does not exist", chunk_number))
+    }
+  }
+
+  fn truncate(&self, chunk_number: u32, length: u64) -> Result<(), String> {
+    self.with_chunk(chunk_number, |contents| contents.truncate(length as usize))
+  }
+
+  fn sync(&self, chunk_number: u32) -> Result<(), String> {
+    self.with_chunk(chunk_number, |_| ())
+  }
+
+  fn list(&self) -> Result<Vec<u32>, String> {
+    Ok(self.chunks.read().unwrap().keys().copied().collect())
+  }
+
+  fn remove(&self, chunk_number: u32) -> Result<(), String> {
+    match self.chunks.write().unwrap().remove(&chunk_number) {
+      Some(_) => Ok(()),
+      None => Err(format!("Chunk {} does not exist", chunk_number))
+    }
+  }
+
+  fn exists(&self, chunk_number: u32) -> bool {
+    self.chunks.read().unwrap().contains_key(&chunk_number)
+  }
+}
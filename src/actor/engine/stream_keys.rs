@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::RwLock;
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::event::{Event, LINK_EVENT_TYPE};
+
+struct StreamKey {
+  // Events of the stream from this id on have encrypted payloads, earlier ones were written
+  // before the stream had a key.
+  first_id : u64,
+  // None once the stream has been shredded.
+  key      : Option<LessSafeKey>
+}
+
+// Per-stream data keys for crypto-shredding.  Payloads of a stream are encrypted with its own key,
+// so destroying the key makes them unreadable for good while the records, and the chunk hashes
+// over them, stay as they are.
+// Each key is kept in a file of its own in the key directory, named after the hex encoded stream
+// name and holding "<first id> <key as hex>", or only "<first id>" once shredded.
+pub struct StreamKeys {
+  dir    : Option<String>,
+  keys   : RwLock<HashMap<String, StreamKey>>,
+  random : SystemRandom
+}
+
+impl StreamKeys {
+  // Payloads are stored as they are.
+  pub fn disabled() -> Self {
+    Self {
+      dir    : None,
+      keys   : RwLock::new(HashMap::new()),
+      random : SystemRandom::new()
+    }
+  }
+
+  pub fn load(dir: &str) -> Result<Self, String> {
+    fs::create_dir_all(dir).map_err(|error| error.to_string())?;
+
+    let mut keys = HashMap::new();
+    for entry in fs::read_dir(dir).map_err(|error| error.to_string())? {
+      let path = entry.map_err(|error| error.to_string())?.path();
+      let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+      let stream_name = match hex::decode(file_name).ok().and_then(|name| String::from_utf8(name).ok()) {
+        Some(stream_name) => stream_name,
+        None => return Err(format!("Unexpected file {:?} in stream key directory", path))
+      };
+
+      let contents = fs::read_to_string(&path).map_err(|error| error.to_string())?;
+      let mut parts = contents.split_whitespace();
+      let first_id = match parts.next().and_then(|first_id| first_id.parse().ok()) {
+        Some(first_id) => first_id,
+        None => return Err(format!("Stream key file {:?} has no first id", path))
+      };
+      let key = match parts.next() {
+        Some(key) => match hex::decode(key).ok().and_then(|key| UnboundKey::new(&AES_256_GCM, &key).ok()) {
+          Some(key) => Some(LessSafeKey::new(key)),
+          None => return Err(format!("Stream key file {:?} has an invalid key", path))
+        },
+        None => None
+      };
+
+      keys.insert(stream_name, StreamKey { first_id, key });
+    }
+
+    Ok(Self {
+      dir    : Some(dir.to_string()),
+      keys   : RwLock::new(keys),
+      random : SystemRandom::new()
+    })
+  }
+
+  pub fn is_shredded(&self, stream_name: &str) -> bool {
+    matches!(self.keys.read().unwrap().get(stream_name), Some(StreamKey { key: None, .. }))
+  }
+
+  // Encrypts the payload and metadata with the stream's key, creating the key on the stream's first
+  // encrypted event.  System streams and links, whose payload is only a reference, are left alone.
+  // Fails if the stream was shredded since the append was validated.
+  pub fn seal(&self, event: &mut Event) -> Result<(), String> {
+    if self.dir.is_none() || !StreamKeys::is_encrypted_stream(event) {
+      return Ok(());
+    }
+
+    let mut keys = self.keys.write().unwrap();
+    if !keys.contains_key(&event.name) {
+      let key = self.create_key(&event.name, event.id)?;
+      keys.insert(event.name.clone(), key);
+    }
+    let key = match &keys[&event.name].key {
+      Some(key) => key,
+      None => return Err(format!("Stream {} has been shredded.", event.name))
+    };
+
+    event.payload = self.seal_text(key, &StreamKeys::associated_data(event.id, false), &event.payload);
+    if !event.metadata.is_empty() {
+      event.metadata = self.seal_text(key, &StreamKeys::associated_data(event.id, true), &event.metadata);
+    }
+    Ok(())
+  }
+
+  // Decrypts a payload and metadata sealed with the stream's key.  Both read as empty for shredded
+  // streams.
+  pub fn open(&self, event: &mut Event) {
+    if !StreamKeys::is_encrypted_stream(event) {
+      return;
+    }
+
+    let keys = self.keys.read().unwrap();
+    let stream_key = match keys.get(&event.name) {
+      Some(stream_key) if event.id >= stream_key.first_id => stream_key,
+      _ => return
+    };
+
+    let open = |text: &str, is_metadata: bool| stream_key.key.as_ref()
+      .and_then(|key| StreamKeys::open_text(key, &StreamKeys::associated_data(event.id, is_metadata), text))
+      .unwrap_or_default();
+    event.payload = open(&event.payload, false);
+    if !event.metadata.is_empty() {
+      event.metadata = open(&event.metadata, true);
+    }
+  }
+
+  // Destroys the stream's key.  The first id is kept so the stream can't get a new key and
+  // later events can still be told apart from those written before it had one.
+  pub fn shred(&self, stream_name: &str) -> Result<(), String> {
+    let dir = match &self.dir {
+      Some(dir) => dir,
+      None => return Err("Stream keys are not enabled.".to_string())
+    };
+
+    let mut keys = self.keys.write().unwrap();
+    let stream_key = match keys.get_mut(stream_name) {
+      Some(stream_key) => stream_key,
+      None => return Err(format!("Stream {} has no key.", stream_name))
+    };
+
+    StreamKeys::write_key_file(dir, stream_name, &stream_key.first_id.to_string())?;
+    stream_key.key = None;
+    Ok(())
+  }
+
+  // Copies the key files to dir, which is left alone when stream keys aren't enabled.  Keys are
+  // only written with the keys locked, so none is copied half written.
+  pub fn copy_to(&self, dir: &str) -> Result<(), String> {
+    let source = match &self.dir {
+      Some(source) => source,
+      None => return Ok(())
+    };
+
+    let keys = self.keys.read().unwrap();
+    fs::create_dir_all(dir).map_err(|error| error.to_string())?;
+    for stream_name in keys.keys() {
+      let file_name = hex::encode(stream_name);
+      fs::copy(format!("{}/{}", source, file_name), format!("{}/{}", dir, file_name)).map_err(|error| error.to_string())?;
+    }
+
+    Ok(())
+  }
+
+  // Returns the nonce followed by the encrypted text and its tag, base64 encoded.
+  fn seal_text(&self, key: &LessSafeKey, associated_data: &[u8], text: &str) -> String {
+    let mut nonce = [0; NONCE_LEN];
+    self.random.fill(&mut nonce).expect("Failed to generate nonce.");
+
+    let mut sealed = text.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+      Nonce::assume_unique_for_key(nonce),
+      Aad::from(associated_data),
+      &mut sealed
+    ).expect("Failed to encrypt event.");
+
+    let mut encoded = nonce.to_vec();
+    encoded.append(&mut sealed);
+    STANDARD.encode(encoded)
+  }
+
+  fn open_text(key: &LessSafeKey, associated_data: &[u8], text: &str) -> Option<String> {
+    let sealed = STANDARD.decode(text).ok()?;
+    let nonce  = Nonce::try_assume_unique_for_key(sealed.get(.. NONCE_LEN)?).ok()?;
+
+    let mut sealed = sealed[NONCE_LEN ..].to_vec();
+    let opened = key.open_in_place(nonce, Aad::from(associated_data), &mut sealed).ok()?;
+    String::from_utf8(opened.to_vec()).ok()
+  }
+
+  // Sealed text is tied to the event's id, and metadata kept apart from payloads so the two can't
+  // be swapped.
+  fn associated_data(event_id: u64, is_metadata: bool) -> Vec<u8> {
+    let mut associated_data = event_id.to_le_bytes().to_vec();
+    if is_metadata {
+      associated_data.push(1);
+    }
+    associated_data
+  }
+
+  fn is_encrypted_stream(event: &Event) -> bool {
+    !event.name.is_empty() && !event.name.starts_with('$') && event.event_type != LINK_EVENT_TYPE
+  }
+
+  fn create_key(&self, stream_name: &str, first_id: u64) -> Result<StreamKey, String> {
+    let mut key = [0; 32];
+    self.random.fill(&mut key).expect("Failed to generate stream key.");
+
+    // The key has to be on disk before anything is encrypted with it.
+    let dir = self.dir.as_ref().unwrap();
+    StreamKeys::write_key_file(dir, stream_name, &format!("{} {}", first_id, hex::encode(key)))?;
+
+    Ok(StreamKey {
+      first_id,
+      key : Some(LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).unwrap()))
+    })
+  }
+
+  fn write_key_file(dir: &str, stream_name: &str, contents: &str) -> Result<(), String> {
+    let path = format!("{}/{}", dir, hex::encode(stream_name));
+    let mut file = File::create(&path).map_err(|error| format!("Failed to write stream key file {}: {}", path, error))?;
+    file.write_all(contents.as_bytes()).map_err(|error| format!("Failed to write stream key file {}: {}", path, error))?;
+    file.sync_all().map_err(|error| format!("Failed to sync stream key file {}: {}", path, error))
+  }
+}
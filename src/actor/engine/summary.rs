@@ -0,0 +1,70 @@
+use ring::digest::SHA256_OUTPUT_LEN;
+use serde::{Serialize, Deserialize};
+
+use super::chunk::LogChunk;
+use super::event::{Event, LINK_EVENT_TYPE, COMMIT_EVENT_TYPE, METADATA_EVENT_TYPE};
+use super::keyring::Keyring;
+
+// What indexing a full chunk takes from it, kept on local disk for archived chunks so the server
+// doesn't have to fetch every one of them back when it starts.  Payloads are only kept for links,
+// commit records and stream metadata, the index doesn't look at any other.
+#[derive(Serialize, Deserialize)]
+pub struct ChunkSummary {
+  // Hash from the chunk's header, covering all of its contents.
+  pub hash    : [u8; SHA256_OUTPUT_LEN],
+  pub version : u8,
+  // Key the chunk is encrypted with, the summary is sealed with it too.
+  pub key_id  : u32,
+  // Offset and record size of every event in the chunk.
+  pub records : Vec<(u32, u32, Event)>
+}
+
+impl ChunkSummary {
+  // None for a chunk that doesn't end where its hash does, or that can't be read without its key.
+  pub fn of(entire_file: &[u8], keyring: &Keyring) -> Option<Self> {
+    let scan = LogChunk::scan(entire_file, keyring).ok()?;
+    if scan.hashed_len != Some(scan.file_len) {
+      return None;
+    }
+
+    let starts  = std::iter::once(scan.header_size).chain(scan.record_ends.iter().cloned());
+    let records = starts.zip(scan.record_ends.iter()).zip(scan.events?)
+      .map(|((start, end), mut event)| {
+        if ![LINK_EVENT_TYPE, COMMIT_EVENT_TYPE, METADATA_EVENT_TYPE].contains(&event.event_type.as_str()) {
+          event.payload.clear();
+        }
+        event.metadata.clear();
+        (start as u32, (end - start) as u32, event)
+      })
+      .collect();
+
+    Some(Self {
+      hash    : scan.hash,
+      version : scan.version,
+      key_id  : scan.key_id,
+      records
+    })
+  }
+
+  // Chunks from before HEADER_VERSION 3 have no commit records, like LogChunk::has_commit_records.
+  pub fn has_commit_records(&self) -> bool {
+    self.version >= 3
+  }
+
+  // Stream names and types are no more readable in the summary than they are in the chunk.  The
+  // key id comes first, the rest is sealed with that key.
+  pub fn seal(&self, chunk_number: u32, keyring: &Keyring) -> Vec<u8> {
+    let serialized = bincode::serialize(self).unwrap();
+
+    let mut sealed = self.key_id.to_le_bytes().to_vec();
+    sealed.append(&mut keyring.seal(self.key_id, &chunk_number.to_le_bytes(), &serialized));
+    sealed
+  }
+
+  // None if the key is missing or the summary isn't one sealed for this chunk.
+  pub fn open(chunk_number: u32, sealed: &[u8], keyring: &Keyring) -> Option<Self> {
+    let key_id = u32::from_le_bytes(sealed.get(.. 4)?.try_into().ok()?);
+    let opened = keyring.open(key_id, &chunk_number.to_le_bytes(), &sealed[4 ..])?;
+    bincode::deserialize(&opened).ok()
+  }
+}
@@ -1,60 +1,292 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use ring::digest::SHA256_OUTPUT_LEN;
+use chrono::Utc;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{oneshot, watch};
+
 use super::index::{Index, IndexElement};
-use super::chunk::LogChunk;
-use super::event::Event;
+use super::chunk::{LogChunk, Compression, WriteError};
+use super::storage::ChunkStorage;
+use super::keyring::Keyring;
+use super::stream_keys::StreamKeys;
+use super::{EngineOptions, AppendError};
+use super::event::{Event, ProposedEvent, COMMIT_EVENT_TYPE, LINK_EVENT_TYPE, commit_payload};
+
+// Most queued appends the writer thread takes on before flushing.
+const MAX_BATCH_SIZE: usize = 256;
+
+// Events to append to one stream, only if its last revision is expected_version when that is set.
+pub struct StreamAppend {
+  pub stream_name      : String,
+  pub events           : Vec<ProposedEvent>,
+  pub expected_version : Option<i64>
+}
+
+// An append waiting in the writer thread's queue.  Only system appends may write to $ streams.
+pub struct AppendRequest {
+  pub streams : Vec<StreamAppend>,
+  pub system  : bool,
+  pub reply   : oneshot::Sender<Result<Vec<i64>, AppendError>>
+}
 
 
 pub struct Writer {
   wchunk  : LogChunk,
-  next_id : u64
+  next_id : u64,
+  // Largest payload in bytes a single event may have.
+  max_event_size : usize,
+  compression    : Compression,
+  storage        : Arc<dyn ChunkStorage>,
+  keyring        : Arc<Keyring>,
+  stream_keys    : Arc<StreamKeys>
 }
 
 impl Writer {
-  pub fn new(mut wchunk_option : Option<LogChunk>, next_id: u64) -> Self {
+  pub fn new(mut wchunk_option : Option<LogChunk>, next_id: u64, options: &EngineOptions, storage: Arc<dyn ChunkStorage>, keyring: Arc<Keyring>, stream_keys: Arc<StreamKeys>) -> Self {
 
     // Empty State?
     if wchunk_option.is_none() {
       println!("Starting from Empty!");
-      wchunk_option = Some(LogChunk::new(1, "chunks/1.chk"));
+      wchunk_option = Some(LogChunk::new(1, storage.clone(), keyring.active_id(), [0; SHA256_OUTPUT_LEN]));
     }
 
-    Self {
+    let mut writer = Self {
       wchunk : wchunk_option.unwrap(),
-      next_id
+      next_id,
+      max_event_size : options.max_event_size,
+      compression    : options.compression,
+      storage,
+      keyring,
+      stream_keys
+    };
+
+    // Chunks written in an older format, or with a key that has since been rotated out, are left
+    // as they are.
+    if !writer.wchunk.is_current_version() || writer.wchunk.key_id != writer.keyring.active_id() {
+      writer.next_chunk();
     }
+
+    writer
   }
 
-  pub fn append_events(&mut self, index: &mut Index, stream_name: String, events: Vec<String> ) -> u64 {
+  // Runs on the writer thread until the engine is dropped.  Whatever appends are queued are taken
+  // on as one batch: each is written as its own transaction, then the chunk is flushed and synced
+  // once for the whole batch before any of them is replied to.  flushed is told the id of the next
+  // event after every flush.
+  pub fn run(mut self, index: Arc<RwLock<Index>>, mut queue: Receiver<AppendRequest>, flushed: watch::Sender<u64>) {
+    while let Some(request) = queue.blocking_recv() {
+      let mut batch = vec![request];
+      while batch.len() < MAX_BATCH_SIZE {
+        match queue.try_recv() {
+          Ok(request) => batch.push(request),
+          Err(_) => break
+        }
+      }
+
+      let mut replies = Vec::with_capacity(batch.len());
+      for AppendRequest{ streams, system, reply } in batch {
+        let result = self.append_transaction(&index, streams, system);
+        replies.push((reply, result));
+      }
 
-    for (i, event_string) in events.iter().enumerate() {
-      let event = &Event::new(
-        self.next_id,
-        &stream_name,
-        event_string
-      ).unwrap();
+      self.wchunk.flush_chunk();
+      flushed.send_replace(self.next_id);
 
-      // Attempt to commit event to chunk only failing if chunk is full.
-      let mut write_result = self.wchunk.attempt_to_write_event(event, i == events.len()-1);
-      if write_result.is_err() {
-        println!("Chunk {} full!", self.wchunk.id);
-        // Allocate another chunk file.
-        let next_chunk_id = self.wchunk.id + 1;
-        self.wchunk = LogChunk::new(next_chunk_id, format!("chunks/{}.chk", next_chunk_id).as_str());
+      for (reply, result) in replies {
+        // The requester may have given up waiting, that doesn't undo the append.
+        let _ = reply.send(result);
+      }
+    }
+  }
+
+  // Writes the events of every stream followed by a single commit record.  Events are only added to
+  // the index once their commit record is written, and recovery ignores events that never got one,
+  // so either all of them are appended or none are.
+  // Returns the last revision of each stream after the append.  A retry of an append that was
+  // written before, going by the ids of its events, isn't written again.
+  fn append_transaction(&mut self, index: &RwLock<Index>, streams: Vec<StreamAppend>, system: bool) -> Result<Vec<i64>, AppendError> {
+    {
+      let index = index.read().unwrap();
+      if Writer::already_appended(&index, &streams)? {
+        return Ok(streams.iter().map(|stream| index.stream_version(&stream.stream_name)).collect());
+      }
+      self.validate(&index, &streams, system)?;
+    }
 
-        write_result = self.wchunk.attempt_to_write_event(event, i == events.len()-1);
-        if write_result.is_err() {
-          panic!("Failed to write event to new chunk file!");
+    let mut events = Vec::new();
+    for stream in streams.iter() {
+      for proposed_event in stream.events.iter() {
+        let mut event = Event::new(
+          self.next_id + events.len() as u64,
+          &stream.stream_name,
+          &proposed_event.event_type,
+          &proposed_event.payload
+        ).unwrap();
+        if let Some(timestamp) = proposed_event.timestamp {
+          event.timestamp = timestamp;
         }
+        event.metadata = proposed_event.metadata.clone();
+        self.stream_keys.seal(&mut event).map_err(AppendError::Failed)?;
+
+        // Events are never split across chunks, so every one has to fit in a chunk of its own.
+        if !LogChunk::fits_in_chunk(&event) {
+          return Err(AppendError::Rejected(format!("Event of {} bytes is too large to be stored.", proposed_event.payload.len() + proposed_event.metadata.len())));
+        }
+        events.push(event);
       }
-      // Add to index
-      let index_element = IndexElement{
-        chunk_number : self.wchunk.id,
-        offset : write_result.unwrap()
-      };
+    }
 
-      index.add(&stream_name, index_element);
+    let mut written = Vec::new();
+    for event in events {
+      let (index_element, size) = self.write_event(&event)?;
+      written.push((event, index_element, size));
       self.next_id +=1;
     }
 
-    self.next_id
+    let mut index = index.write().unwrap();
+
+    if !written.is_empty() {
+      // The commit record reuses the id of the last event, it isn't an event of its own.
+      let event_ids = Writer::event_ids(&streams);
+      let commit = Event::new(self.next_id-1, "", COMMIT_EVENT_TYPE, &commit_payload(&event_ids)).unwrap();
+      self.write_event(&commit)?;
+      index.add_event_ids(event_ids.into_iter().flatten());
+
+      // Add to index
+      for (event, index_element, size) in written {
+        index.add(&event, index_element, size);
+      }
+    }
+
+    Ok(streams.iter().map(|stream| index.stream_version(&stream.stream_name)).collect())
+  }
+
+  // The id given to each event of the append, in the order they are written.
+  fn event_ids(streams: &[StreamAppend]) -> Vec<Option<String>> {
+    streams.iter()
+      .flat_map(|stream| stream.events.iter().map(|event| event.event_id.clone()))
+      .collect()
+  }
+
+  // Whether every event given an id was appended before.  Appends are written whole or not at all,
+  // so an append where only some of them were is a different append reusing ids.
+  fn already_appended(index: &Index, streams: &[StreamAppend]) -> Result<bool, AppendError> {
+    let event_ids : Vec<&str> = streams.iter()
+      .flat_map(|stream| stream.events.iter().filter_map(|event| event.event_id.as_deref()))
+      .collect();
+    if event_ids.is_empty() {
+      return Ok(false);
+    }
+
+    let mut seen = HashSet::new();
+    if let Some(event_id) = event_ids.iter().find(|event_id| !seen.insert(**event_id)) {
+      return Err(AppendError::Rejected(format!("Event id {} is given to more than one event.", event_id)));
+    }
+
+    let appended = event_ids.iter().filter(|event_id| index.has_event_id(event_id)).count();
+    match appended {
+      0 => Ok(false),
+      _ if appended == event_ids.len() => Ok(true),
+      _ => {
+        let event_id = event_ids.iter().find(|event_id| index.has_event_id(event_id)).unwrap();
+        Err(AppendError::Rejected(format!("Event id {} was already appended.", event_id)))
+      }
+    }
+  }
+
+  fn validate(&self, index: &Index, streams: &[StreamAppend], system: bool) -> Result<(), AppendError> {
+    // Timestamps given with events can't go back from the last event written, nor be in the
+    // future, or reading by time would skip events.
+    let mut last_timestamp = index.last_timestamp();
+    let now = Utc::now().timestamp();
+    for event in streams.iter().flat_map(|stream| stream.events.iter()) {
+      if let Some(timestamp) = event.timestamp {
+        if timestamp < last_timestamp || timestamp > now {
+          return Err(AppendError::Rejected(format!("Timestamp {} is not between the last event's, {}, and now.", timestamp, last_timestamp)));
+        }
+        last_timestamp = timestamp;
+      }
+    }
+
+    // The commit record carries the ids of all the events, and has to fit in a chunk like they do.
+    let event_ids = Writer::event_ids(streams);
+    let commit = Event::new(self.next_id, "", COMMIT_EVENT_TYPE, &commit_payload(&event_ids)).unwrap();
+    if !LogChunk::fits_in_chunk(&commit) {
+      return Err(AppendError::Rejected(format!("The ids of {} events are too large to be stored in one append.", event_ids.len())));
+    }
+
+    for stream in streams {
+      for event in stream.events.iter() {
+        let event_size = event.payload.len() + event.metadata.len();
+        if event_size > self.max_event_size {
+          return Err(AppendError::Rejected(format!("Event of {} bytes is larger than the maximum event size of {} bytes.", event_size, self.max_event_size)));
+        }
+      }
+
+      // You can't append events to "reserved" stream names, $ is kept for $all and the other
+      // system projections.
+      if !system && stream.stream_name.starts_with('$') {
+        return Err(AppendError::Rejected("Illegal stream_name parameter.".to_string()));
+      }
+
+      if index.is_deleted(&stream.stream_name) {
+        return Err(AppendError::Rejected(format!("Stream {} has been deleted.", stream.stream_name)));
+      }
+
+      if self.stream_keys.is_shredded(&stream.stream_name) {
+        return Err(AppendError::Rejected(format!("Stream {} has been shredded.", stream.stream_name)));
+      }
+
+      // Links have to point at an event that exists.
+      for event in stream.events.iter().filter(|event| event.event_type == LINK_EVENT_TYPE) {
+        if index.link_target(&event.payload).is_none() {
+          return Err(AppendError::Rejected(format!("Link target {} does not exist.", event.payload)));
+        }
+      }
+
+      if let Some(expected_version) = stream.expected_version {
+        let current_version = index.stream_version(&stream.stream_name);
+        if expected_version != current_version {
+          return Err(AppendError::WrongExpectedVersion(format!("Expected version {} of stream {} but it is at {}.", expected_version, stream.stream_name, current_version)));
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  fn write_event(&mut self, event: &Event) -> Result<(IndexElement, u32), AppendError> {
+    // Attempt to commit event to chunk, moving on to the next chunk if it's full.
+    let mut write_result = self.wchunk.attempt_to_write_event(event, self.compression, &self.keyring);
+    if write_result == Err(WriteError::ChunkFull) {
+      println!("Chunk {} full!", self.wchunk.id);
+      self.next_chunk();
+
+      write_result = self.wchunk.attempt_to_write_event(event, self.compression, &self.keyring);
+    }
+
+    let (offset, size) = match write_result {
+      Ok(written) => written,
+      Err(WriteError::ChunkFull) => return Err(AppendError::Rejected(format!("Event {} doesn't fit in a chunk of its own.", event.id))),
+      Err(WriteError::Compression(error)) => return Err(AppendError::Failed(error))
+    };
+    let index_element = IndexElement{
+      chunk_number : self.wchunk.id,
+      offset
+    };
+
+    Ok((index_element, size))
+  }
+
+  // Allocate another chunk file.
+  fn next_chunk(&mut self) {
+    let next_chunk_id = self.wchunk.id + 1;
+    self.wchunk = LogChunk::new(
+      next_chunk_id,
+      self.storage.clone(),
+      self.keyring.active_id(),
+      self.wchunk.hash()
+    );
   }
 }
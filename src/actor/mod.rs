@@ -1,49 +1,110 @@
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use actix::{Actor, Context, Handler, Message, AsyncContext, fut::{wrap_future}};
+use actix::{Actor, Context, Handler, Message, AsyncContext, ResponseFuture};
 use self::engine::Engine;
-use super::api::ReadStreamResponse;
-use tokio::sync::{mpsc::Sender};
-use tonic::Status;
+use self::projection::{Projections, ProjectionError};
+use super::api::ProjectionStatus;
+use super::store::Store;
 
 // Pull in modules defined in subdirs below
 pub mod engine;
+pub mod projection;
+
+// How often running projections are fed new events.
+const PROJECTION_INTERVAL: Duration = Duration::from_millis(500);
 
 // Define Actor Messages
 #[derive(Message, Debug)]
-#[rtype(result = "Result<u64, String>")]
-pub struct AppendToStream {
-  pub stream_name : String,
-  pub events      : Vec<String>
+#[rtype(result = "Result<ProjectionStatus, ProjectionError>")]
+pub struct CreateProjection {
+  pub name          : String,
+  pub source_stream : String,
+  pub script        : String
+}
+
+#[derive(Debug)]
+pub enum ProjectionAction {
+  Start,
+  Stop,
+  Reset,
+  Status
 }
 
 #[derive(Message, Debug)]
-#[rtype(result = "Result<(), ()>")]
-pub struct ReadStream {
-  pub stream_name : String,
-  pub tx_channel  : Sender<Result<ReadStreamResponse, Status>>,
-  pub stream_position: u64
+#[rtype(result = "Result<ProjectionStatus, ProjectionError>")]
+pub struct ControlProjection {
+  pub name   : String,
+  pub action : ProjectionAction
 }
 
-// Define Actor Execution Context in this struct.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<ProjectionStatus>, ()>")]
+pub struct ListProjections {}
+
+// Runs the projections against the store, everything else is done on the Store directly.
+// Projections read and append blocking, so their work is done on Tokio's blocking threads rather
+// than in the actor.
 #[derive(Clone)]
 pub struct BetterStoreActor {
-  engine : Arc<Mutex<Engine>>
+  store       : Store,
+  projections : Arc<Mutex<Projections>>,
+  // Set while projections are being run, so a slow run isn't started again on top of itself.
+  running     : Arc<AtomicBool>
+}
+
+impl Default for BetterStoreActor {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl BetterStoreActor {
   pub fn new() -> Self {
+    Self::with_store(Store::open(Default::default()))
+  }
+
+  pub fn with_store(store: Store) -> Self {
+    let projections = Projections::load(store.engine());
+
     Self {
-      engine : Arc::new(Mutex::new(Engine::new()))
+      store,
+      projections : Arc::new(Mutex::new(projections)),
+      running     : Arc::new(AtomicBool::new(false))
     }
   }
+
+  fn run_projections(&mut self) {
+    if self.running.swap(true, Ordering::AcqRel) {
+      return;
+    }
+
+    let running = self.running.clone();
+    let run = self.with_projections(|projections, engine| projections.run(engine));
+    actix::spawn(async move {
+      run.await;
+      running.store(false, Ordering::Release);
+    });
+  }
+
+  // Runs f on a blocking thread with the projections locked.
+  fn with_projections<T, F>(&self, f: F) -> ResponseFuture<T>
+      where F: FnOnce(&mut Projections, &Engine) -> T + Send + 'static, T: Send + 'static {
+    let store       = self.store.clone();
+    let projections = self.projections.clone();
+    let task = tokio::task::spawn_blocking(move || f(&mut projections.lock().unwrap(), store.engine()));
+    Box::pin(async move { task.await.expect("Projection task panicked.") })
+  }
 }
 
 impl Actor for BetterStoreActor {
   type Context = Context<Self>;
 
-  fn started(&mut self, _ctx: &mut Context<Self>) {
+  fn started(&mut self, ctx: &mut Context<Self>) {
     println!("BetterStoreActor is started!");
+
+    ctx.run_interval(PROJECTION_INTERVAL, |actor, _ctx| actor.run_projections());
   }
 
   fn stopped(&mut self, _ctx: &mut Context<Self>) {
@@ -51,57 +112,31 @@ impl Actor for BetterStoreActor {
   }
 }
 
-impl Handler<AppendToStream> for BetterStoreActor {
-  type Result = Result<u64, String>;
+impl Handler<CreateProjection> for BetterStoreActor {
+  type Result = ResponseFuture<Result<ProjectionStatus, ProjectionError>>;
 
-  fn handle(&mut self, msg: AppendToStream, _ctx: &mut Context<Self>) -> Result<u64, String> {
-    let engine = self.engine.clone();
-    let mut engine = engine.lock().unwrap();
-    engine.append_events(
-      msg.stream_name,
-      msg.events
-    ) 
+  fn handle(&mut self, msg: CreateProjection, _ctx: &mut Context<Self>) -> Self::Result {
+    self.with_projections(move |projections, engine| projections.create(engine, &msg.name, &msg.source_stream, &msg.script))
   }
 }
 
-impl Handler<ReadStream> for BetterStoreActor {
-  type Result = Result<(), ()>;
+impl Handler<ControlProjection> for BetterStoreActor {
+  type Result = ResponseFuture<Result<ProjectionStatus, ProjectionError>>;
 
-  fn handle(&mut self, msg: ReadStream, ctx: &mut Context<Self>) -> Self::Result {
-    let engine = self.engine.clone();
-
-    let fut = 
-      async move {
-        let mut engine = engine.lock().unwrap();
-
-        engine.read_stream(
-          msg.stream_name,
-          msg.stream_position,
-          msg.tx_channel
-        ).await
-    };
+  fn handle(&mut self, msg: ControlProjection, _ctx: &mut Context<Self>) -> Self::Result {
+    self.with_projections(move |projections, engine| match msg.action {
+      ProjectionAction::Start  => projections.start(engine, &msg.name),
+      ProjectionAction::Stop   => projections.stop(engine, &msg.name),
+      ProjectionAction::Reset  => projections.reset(engine, &msg.name),
+      ProjectionAction::Status => projections.status(&msg.name)
+    })
+  }
+}
 
-    let fut = wrap_future(fut);
+impl Handler<ListProjections> for BetterStoreActor {
+  type Result = ResponseFuture<Result<Vec<ProjectionStatus>, ()>>;
 
-    ctx.spawn(fut);
-    Ok(())
+  fn handle(&mut self, _msg: ListProjections, _ctx: &mut Context<Self>) -> Self::Result {
+    self.with_projections(|projections, _| Ok(projections.list()))
   }
-  // Implementation with no Arc or Mutex guard.
-  // NOTE: clone causes each clone to have different index state between read streams and write streams
-  // fn handle(&mut self, msg: ReadStream, ctx: &mut Context<Self>) -> Self::Result {
-  //   let mut this = self.clone();
-  //   let fut = 
-  //     async move {
-  //       this.engine.read_stream(
-  //         msg.stream_name,
-  //         msg.stream_position,
-  //         msg.tx_channel
-  //       ).await
-  //   };
-
-  //   let fut = wrap_future(fut);
-
-  //   ctx.spawn(fut);
-  //   Ok(())
-  // }
-}
\ No newline at end of file
+}
@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rhai::{Dynamic, Map, Scope, AST};
+use serde::{Serialize, Deserialize};
+
+use super::engine::{Engine, ReadError};
+use super::engine::event::{Event, ProposedEvent, LINK_EVENT_TYPE};
+use super::super::api::ProjectionStatus;
+
+// Definitions and start/stop history of every projection live in this stream, each projection's
+// checkpoints in $projections-<name>-checkpoint.
+const PROJECTIONS_STREAM: &str = "$projections";
+
+// How many source events a projection handles per run before it writes a checkpoint.
+const BATCH_SIZE: usize = 500;
+
+// Limits that keep a script from hogging the server, checked for every handled event.
+const MAX_OPERATIONS: u64     = 1_000_000;
+const MAX_CALL_LEVELS: usize  = 32;
+const MAX_STRING_SIZE: usize  = 1_000_000;
+const MAX_COLLECTION_SIZE: usize = 100_000;
+
+#[derive(Debug)]
+pub enum ProjectionError {
+  NotFound(String),
+  AlreadyExists(String),
+  InvalidScript(String),
+  // The store refused the events recording the change.
+  Append(String)
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProjectionDefinition {
+  name          : String,
+  source_stream : String,
+  script        : String
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProjectionControl {
+  name  : String,
+  error : Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+  position : u64,
+  state    : Dynamic,
+  // Events emitted along with the checkpoint, which follow it in $all.
+  #[serde(default)]
+  emitted  : u64
+}
+
+struct Projection {
+  source_stream : String,
+  ast           : AST,
+  running       : bool,
+  position      : u64,
+  state         : Dynamic,
+  // Events still to come in $all that the projection emitted itself.
+  skip          : u64,
+  error         : Option<String>
+}
+
+pub struct Projections {
+  scripting   : rhai::Engine,
+  emitted     : Arc<Mutex<Vec<(String, ProposedEvent)>>>,
+  projections : BTreeMap<String, Projection>
+}
+
+impl Projections {
+  // Sets up the script engine and brings back every projection recorded in the store.
+  pub fn load(engine: &Engine) -> Self {
+    let mut scripting = rhai::Engine::new();
+
+    // Scripts can't reach outside the sandbox, but they can still loop forever or eat memory.
+    scripting.set_max_operations(MAX_OPERATIONS);
+    scripting.set_max_call_levels(MAX_CALL_LEVELS);
+    scripting.set_max_string_size(MAX_STRING_SIZE);
+    scripting.set_max_array_size(MAX_COLLECTION_SIZE);
+    scripting.set_max_map_size(MAX_COLLECTION_SIZE);
+    scripting.disable_symbol("eval");
+
+    // emit(stream, event_type, payload) queues an event that is appended once the batch is done.
+    let emitted = Arc::new(Mutex::new(Vec::new()));
+    let sink    = emitted.clone();
+    scripting.register_fn("emit", move |stream_name: &str, event_type: &str, payload: &str| {
+      sink.lock().unwrap().push((stream_name.to_string(), ProposedEvent::new(event_type, payload)));
+    });
+
+    // link_to(stream, event) queues a link to an event the script was handed.
+    let sink = emitted.clone();
+    scripting.register_fn("link_to", move |stream_name: &str, event: Map| {
+      let revision = event.get("revision").and_then(|revision| revision.as_int().ok()).unwrap_or(0);
+      let source   = event.get("stream").map(|source| source.to_string()).unwrap_or_default();
+      sink.lock().unwrap().push((stream_name.to_string(), ProposedEvent::new(LINK_EVENT_TYPE, &format!("{}@{}", revision, source))));
+    });
+
+    let mut this = Self {
+      scripting,
+      emitted,
+      projections : BTreeMap::new()
+    };
+
+    // No projections have ever been created if the stream doesn't exist.
+    let history = match engine.read_events(PROJECTIONS_STREAM, 0, usize::MAX) {
+      Ok(history) => history,
+      Err(ReadError::StreamNotFound(_)) => Vec::new(),
+      Err(error) => panic!("Failed to read the projections: {}", error)
+    };
+
+    let mut faulted = Vec::new();
+    for (_, event) in history {
+      this.replay(engine, &event, &mut faulted);
+    }
+
+    // A projection that can't be brought back, such as one whose script no longer compiles after
+    // the script engine changed, only faults itself.  Its faults are kept over the start and stop
+    // history replayed after them.
+    for (name, error) in faulted {
+      println!("Projection {} faulted: {}", name, error);
+      if let Some(projection) = this.projections.get_mut(&name) {
+        projection.running = false;
+        projection.error   = Some(error);
+      }
+    }
+
+    this
+  }
+
+  // Brings back what an event of the projections stream recorded, adding projections that can't be
+  // brought back whole to faulted with the reason.  Those missing their script or checkpoint are
+  // left without a handler, so they can't be started again until they can be loaded.
+  fn replay(&mut self, engine: &Engine, event: &Event, faulted: &mut Vec<(String, String)>) {
+    if event.event_type == "$ProjectionCreated" {
+      let definition : ProjectionDefinition = match serde_json::from_str(&event.payload) {
+        Ok(definition) => definition,
+        Err(error) => {
+          self.replay_unreadable(event, format!("Definition is unreadable: {}", error), faulted);
+          return;
+        }
+      };
+
+      let ast = match self.scripting.compile(&definition.script) {
+        Ok(ast) => ast,
+        Err(error) => {
+          faulted.push((definition.name.clone(), format!("Script does not compile: {}", error)));
+          AST::empty()
+        }
+      };
+      let mut projection = Projection {
+        source_stream : definition.source_stream,
+        state         : self.init_state(&ast),
+        ast,
+        running       : false,
+        position      : 0,
+        skip          : 0,
+        error         : None
+      };
+
+      // Pick up from the last checkpoint, if it got as far as writing one.  Starting over from the
+      // beginning instead would emit everything again.
+      let checkpoint_stream = Projections::checkpoint_stream(&definition.name);
+      if let Ok(info) = engine.get_stream_info(&checkpoint_stream) {
+        let checkpoint = engine.read_events(&checkpoint_stream, info.last_revision, 1)
+          .map_err(|error| error.to_string())
+          .and_then(|last| match last.first() {
+            Some((_, event)) => serde_json::from_str::<Checkpoint>(&event.payload).map_err(|error| error.to_string()),
+            None => Err("Checkpoint stream is empty".to_string())
+          });
+        match checkpoint {
+          Ok(checkpoint) => {
+            projection.position = checkpoint.position;
+            projection.state    = checkpoint.state;
+          }
+          Err(error) => {
+            faulted.push((definition.name.clone(), format!("Failed to read the last checkpoint: {}", error)));
+            projection.ast = AST::empty();
+          }
+        }
+      }
+
+      self.projections.insert(definition.name, projection);
+      return;
+    }
+
+    let control : ProjectionControl = match serde_json::from_str(&event.payload) {
+      Ok(control) => control,
+      Err(error) => {
+        self.replay_unreadable(event, format!("{} is unreadable: {}", event.event_type, error), faulted);
+        return;
+      }
+    };
+    let projection = match self.projections.get_mut(&control.name) {
+      Some(projection) => projection,
+      None => {
+        println!("Ignoring {} of projection {}, which was never created", event.event_type, control.name);
+        return;
+      }
+    };
+    match event.event_type.as_str() {
+      "$ProjectionStarted" => {
+        projection.running = true;
+        projection.error   = None;
+      }
+      "$ProjectionStopped" | "$ProjectionFaulted" => {
+        projection.running = false;
+        projection.error   = control.error;
+      }
+      _ => println!("Unknown projection event {}", event.event_type)
+    }
+  }
+
+  // Faults the projection an unreadable event is about, as far as its name can be made out.  A
+  // definition that can't be read leaves a projection that can't be started in its place.
+  fn replay_unreadable(&mut self, event: &Event, error: String, faulted: &mut Vec<(String, String)>) {
+    let name = serde_json::from_str::<serde_json::Value>(&event.payload).ok()
+      .and_then(|payload| payload.get("name").and_then(|name| name.as_str()).map(str::to_string));
+    let name = match name {
+      Some(name) => name,
+      None => {
+        println!("Ignoring {} of no projection that can be made out: {}", event.event_type, error);
+        return;
+      }
+    };
+
+    if event.event_type == "$ProjectionCreated" {
+      self.projections.entry(name.clone()).or_insert(Projection {
+        source_stream : String::new(),
+        ast           : AST::empty(),
+        running       : false,
+        position      : 0,
+        state         : Dynamic::from_map(Map::new()),
+        skip          : 0,
+        error         : None
+      });
+    }
+    faulted.push((name, error));
+  }
+
+  // A projection's state starts out as whatever its init() returns, or an empty map without one.
+  fn init_state(&self, ast: &AST) -> Dynamic {
+    let has_init = ast.iter_functions().any(|function| function.name == "init" && function.params.is_empty());
+    if !has_init {
+      return Dynamic::from_map(Map::new());
+    }
+
+    match self.scripting.call_fn::<Dynamic>(&mut Scope::new(), ast, "init", ()) {
+      Ok(state) => state,
+      Err(error) => {
+        println!("Projection init() failed: {}", error);
+        Dynamic::from_map(Map::new())
+      }
+    }
+  }
+
+  pub fn create(&mut self, engine: &Engine, name: &str, source_stream: &str, script: &str) -> Result<ProjectionStatus, ProjectionError> {
+    if self.projections.contains_key(name) {
+      return Err(ProjectionError::AlreadyExists(name.to_string()));
+    }
+    if name.is_empty() {
+      return Err(ProjectionError::InvalidScript("A projection needs a name.".to_string()));
+    }
+
+    let ast = match self.scripting.compile(script) {
+      Ok(ast) => ast,
+      Err(error) => return Err(ProjectionError::InvalidScript(error.to_string()))
+    };
+    if !Projections::has_handle(&ast) {
+      return Err(ProjectionError::InvalidScript("Script must define handle(state, event).".to_string()));
+    }
+
+    let source_stream = match source_stream {
+      "" => "$all",
+      _ => source_stream
+    };
+    let definition = ProjectionDefinition {
+      name          : name.to_string(),
+      source_stream : source_stream.to_string(),
+      script        : script.to_string()
+    };
+    Projections::record(engine, "$ProjectionCreated", serde_json::to_string(&definition).unwrap())
+      .map_err(ProjectionError::Append)?;
+
+    let projection = Projection {
+      source_stream : definition.source_stream,
+      state         : self.init_state(&ast),
+      ast,
+      running       : false,
+      position      : 0,
+      skip          : 0,
+      error         : None
+    };
+    self.projections.insert(name.to_string(), projection);
+
+    self.start(engine, name)
+  }
+
+  pub fn start(&mut self, engine: &Engine, name: &str) -> Result<ProjectionStatus, ProjectionError> {
+    let projection = self.find(name)?;
+    // Only projections that couldn't be brought back when loading lack a handler.
+    if !Projections::has_handle(&projection.ast) {
+      return Err(ProjectionError::InvalidScript(projection.error.clone().unwrap_or_default()));
+    }
+
+    Projections::record_control(engine, "$ProjectionStarted", name, None).map_err(ProjectionError::Append)?;
+    projection.running = true;
+    projection.error   = None;
+    self.status(name)
+  }
+
+  pub fn stop(&mut self, engine: &Engine, name: &str) -> Result<ProjectionStatus, ProjectionError> {
+    let projection = self.find(name)?;
+    Projections::record_control(engine, "$ProjectionStopped", name, None).map_err(ProjectionError::Append)?;
+    projection.running = false;
+    self.status(name)
+  }
+
+  pub fn reset(&mut self, engine: &Engine, name: &str) -> Result<ProjectionStatus, ProjectionError> {
+    self.stop(engine, name)?;
+
+    let state      = self.init_state(&self.projections[name].ast);
+    let projection = self.find(name)?;
+    projection.position = 0;
+    projection.state    = state;
+    projection.skip     = 0;
+    projection.error    = None;
+    Projections::checkpoint(engine, name, projection).map_err(ProjectionError::Append)?;
+
+    self.status(name)
+  }
+
+  pub fn status(&self, name: &str) -> Result<ProjectionStatus, ProjectionError> {
+    let projection = match self.projections.get(name) {
+      Some(projection) => projection,
+      None => return Err(ProjectionError::NotFound(name.to_string()))
+    };
+
+    let status = match (projection.running, &projection.error) {
+      (true, _)        => "Running",
+      (false, None)    => "Stopped",
+      (false, Some(_)) => "Faulted"
+    };
+
+    Ok(ProjectionStatus {
+      name          : name.to_string(),
+      source_stream : projection.source_stream.clone(),
+      status        : status.to_string(),
+      position      : projection.position,
+      state         : serde_json::to_string(&projection.state).unwrap_or_default(),
+      error         : projection.error.clone().unwrap_or_default()
+    })
+  }
+
+  pub fn list(&self) -> Vec<ProjectionStatus> {
+    self.projections.keys().map(|name| self.status(name).unwrap()).collect()
+  }
+
+  // Feeds every running projection the next batch of events from its source stream.
+  pub fn run(&mut self, engine: &Engine) {
+    for (name, projection) in self.projections.iter_mut().filter(|(_, projection)| projection.running) {
+      let events = match engine.read_events(&projection.source_stream, projection.position, BATCH_SIZE) {
+        Ok(events) => events,
+        // Nothing has been written to the source stream yet.  Reading past its end isn't an error.
+        Err(ReadError::StreamNotFound(_)) | Err(ReadError::RevisionNotFound(..)) => continue,
+        // Tried again on the next run.
+        Err(error) => {
+          println!("Projection {} couldn't read {}: {}", name, projection.source_stream, error);
+          continue;
+        }
+      };
+      if events.is_empty() {
+        continue;
+      }
+
+      // Nothing changes until the batch's emitted events and checkpoint are written, so a batch that
+      // fails to be written is handled again once the projection is restarted.
+      let checkpoint_stream = Projections::checkpoint_stream(name);
+      let mut position = projection.position;
+      let mut state    = projection.state.clone();
+      let mut skip     = projection.skip;
+      let mut handled  = 0;
+      let mut error    = None;
+      for (revision, event) in events {
+        position += 1;
+
+        // In $all the projection's own checkpoints come right before the events emitted with them,
+        // which it must not be handed back.
+        if event.name == checkpoint_stream {
+          skip = serde_json::from_str::<Checkpoint>(&event.payload).map(|checkpoint| checkpoint.emitted).unwrap_or(0);
+          continue;
+        }
+        if skip > 0 {
+          skip -= 1;
+          continue;
+        }
+
+        // System streams, including the projections' own checkpoints, are never handed to scripts.
+        if event.name.starts_with('$') {
+          continue;
+        }
+
+        handled += 1;
+        let emitted_before = self.emitted.lock().unwrap().len();
+        let event_map = Projections::event_map(&self.scripting, revision, event);
+        let result = self.scripting.call_fn::<Dynamic>(
+          &mut Scope::new(),
+          &projection.ast,
+          "handle",
+          (state.clone(), event_map)
+        );
+
+        match result {
+          Ok(handled_state) => state = handled_state,
+          Err(script_error) => {
+            // Whatever the failed call emitted goes along with it.
+            self.emitted.lock().unwrap().truncate(emitted_before);
+            position -= 1;
+            error = Some(script_error.to_string());
+            break;
+          }
+        }
+      }
+
+      // Only checkpoint when a script actually ran, writing one for skipped system events would
+      // just give a projection of $all another event to skip.
+      let emitted : Vec<(String, ProposedEvent)> = self.emitted.lock().unwrap().drain(..).collect();
+      let written = match handled {
+        0 => Ok(()),
+        _ => Projections::commit(engine, name, Checkpoint { position, state: state.clone(), emitted: emitted.len() as u64 }, emitted)
+      };
+      match written {
+        Ok(()) => {
+          projection.position = position;
+          projection.state    = state;
+          projection.skip     = skip;
+        }
+        Err(write_error) => error = Some(write_error)
+      }
+
+      if let Some(error) = error {
+        println!("Projection {} faulted: {}", name, error);
+        projection.running = false;
+        projection.error   = Some(error.clone());
+        if let Err(record_error) = Projections::record_control(engine, "$ProjectionFaulted", name, Some(error)) {
+          println!("Failed to record that projection {} faulted: {}", name, record_error);
+        }
+      }
+    }
+  }
+
+  // Writes the checkpoint and the events emitted since the last one in one transaction, the
+  // checkpoint first, grouping runs of events for the same stream.
+  fn commit(engine: &Engine, name: &str, checkpoint: Checkpoint, emitted: Vec<(String, ProposedEvent)>) -> Result<(), String> {
+    // Appended as system events along with the checkpoint, so the check for reserved names is
+    // made here.
+    if let Some((stream_name, _)) = emitted.iter().find(|(stream_name, _)| stream_name.starts_with('$')) {
+      return Err(format!("Failed to emit to {}: Illegal stream_name parameter.", stream_name));
+    }
+
+    let checkpoint = ProposedEvent::new("$ProjectionCheckpoint", &serde_json::to_string(&checkpoint).unwrap());
+    let mut streams = vec![(Projections::checkpoint_stream(name), vec![checkpoint])];
+    for (stream_name, event) in emitted {
+      match streams.last_mut() {
+        Some((last, events)) if *last == stream_name => events.push(event),
+        _ => streams.push((stream_name, vec![event]))
+      }
+    }
+
+    engine.append_system_streams(streams).map_err(|error| format!("Failed to write emitted events and checkpoint: {}", error))
+  }
+
+  // Scripts see an event as a map, with body holding the payload parsed as JSON when it is an object.
+  fn event_map(scripting: &rhai::Engine, revision: u64, event: Event) -> Map {
+    let body = match scripting.parse_json(&event.payload, true) {
+      Ok(body) => Dynamic::from_map(body),
+      Err(_) => Dynamic::UNIT
+    };
+
+    let mut map = Map::new();
+    map.insert("stream".into(), event.name.into());
+    map.insert("type".into(), event.event_type.into());
+    map.insert("id".into(), (event.id as i64).into());
+    map.insert("revision".into(), (revision as i64).into());
+    map.insert("timestamp".into(), event.timestamp.into());
+    map.insert("data".into(), event.payload.into());
+    map.insert("body".into(), body);
+    map
+  }
+
+  fn has_handle(ast: &AST) -> bool {
+    ast.iter_functions().any(|function| function.name == "handle" && function.params.len() == 2)
+  }
+
+  fn find(&mut self, name: &str) -> Result<&mut Projection, ProjectionError> {
+    match self.projections.get_mut(name) {
+      Some(projection) => Ok(projection),
+      None => Err(ProjectionError::NotFound(name.to_string()))
+    }
+  }
+
+  fn checkpoint(engine: &Engine, name: &str, projection: &Projection) -> Result<(), String> {
+    let checkpoint = Checkpoint {
+      position : projection.position,
+      state    : projection.state.clone(),
+      emitted  : 0
+    };
+
+    engine.append_system_events(
+      Projections::checkpoint_stream(name),
+      vec![ProposedEvent::new("$ProjectionCheckpoint", &serde_json::to_string(&checkpoint).unwrap())]
+    )
+  }
+
+  fn checkpoint_stream(name: &str) -> String {
+    format!("{}-{}-checkpoint", PROJECTIONS_STREAM, name)
+  }
+
+  fn record_control(engine: &Engine, event_type: &str, name: &str, error: Option<String>) -> Result<(), String> {
+    let control = ProjectionControl {
+      name : name.to_string(),
+      error
+    };
+    Projections::record(engine, event_type, serde_json::to_string(&control).unwrap())
+  }
+
+  fn record(engine: &Engine, event_type: &str, payload: String) -> Result<(), String> {
+    engine.append_system_events(
+      PROJECTIONS_STREAM.to_string(),
+      vec![ProposedEvent::new(event_type, &payload)]
+    )
+  }
+}
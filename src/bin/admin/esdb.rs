@@ -0,0 +1,67 @@
+use std::io::Read;
+
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::transfer::ExportedEvent;
+
+// An event as EventStoreDB and the tools around it write them out, under either of the names its
+// HTTP and gRPC APIs use:
+//   {"streamId": "order-1", "eventNumber": 0, "eventType": "Placed", "data": {...},
+//    "metadata": {...}, "created": "2024-05-01T12:00:00.1234567Z"}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DumpedEvent {
+    #[serde(alias = "eventStreamId")]
+    stream_id    : String,
+    #[serde(alias = "streamRevision")]
+    event_number : u64,
+    event_type   : String,
+    #[serde(default)]
+    data         : Value,
+    #[serde(default, alias = "metaData")]
+    metadata     : Value,
+    #[serde(alias = "updated")]
+    created      : String
+}
+
+// Reads a dump, JSON Lines or a JSON array of events, in the order the events were written in.
+// System streams, those starting with $, are left out as betterstore keeps its own.
+pub fn read_dump(input: impl Read) -> impl Iterator<Item = Result<ExportedEvent, String>> {
+    serde_json::Deserializer::from_reader(input).into_iter::<Value>()
+        .flat_map(|value| match value {
+            Ok(Value::Array(values)) => values.into_iter().map(Ok).collect(),
+            Ok(value) => vec![Ok(value)],
+            Err(error) => vec![Err(error.to_string())]
+        })
+        .map(|value| value.and_then(exported_event))
+        .filter(|event| !matches!(event, Ok(event) if event.stream.starts_with('$')))
+}
+
+fn exported_event(value: Value) -> Result<ExportedEvent, String> {
+    let event : DumpedEvent = serde_json::from_value(value).map_err(|error| error.to_string())?;
+    let created = DateTime::parse_from_rfc3339(&event.created)
+        .map_err(|error| format!("Event {} of {} was created {}: {}", event.event_number, event.stream_id, event.created, error))?;
+
+    Ok(ExportedEvent {
+        // Payloads are strings, JSON data is kept as its text.
+        payload    : match event.data {
+            Value::String(data) => data,
+            Value::Null => String::new(),
+            data => data.to_string()
+        },
+        metadata   : match event.metadata {
+            Value::Object(ref metadata) if metadata.is_empty() => None,
+            Value::String(ref metadata) if metadata.is_empty() => None,
+            Value::Null => None,
+            metadata => Some(metadata)
+        },
+        stream     : event.stream_id,
+        revision   : event.event_number,
+        // Positions in the other store mean nothing here, the events get new ids as they're imported.
+        id         : 0,
+        timestamp  : created.timestamp(),
+        event_type : event.event_type
+    })
+}
@@ -0,0 +1,342 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+use std::sync::Arc;
+
+use chrono::DateTime;
+use std::future::Future;
+use tokio::runtime::Runtime;
+use tonic::transport::Channel;
+
+use betterstore::actor::engine::admin::{self, ChunkProblem, ChunkReport, RestorePoint};
+use betterstore::actor::engine::{open_archive, Archive};
+use betterstore::api::BackupRequest;
+use betterstore::api::events_client::EventsClient;
+
+use transfer::ImportOptions;
+
+mod esdb;
+mod transfer;
+
+const USAGE: &str = "usage: betterstore-admin [check] [data dir] [--key-file <path>] [--archive <location>] [--repair] [--quarantine]
+       betterstore-admin backup <target dir> [--since <backup dir>] [--server <url>]
+       betterstore-admin restore <backup dir>... <data dir> [--key-file <path>] [--stream-keys-dir <path>]
+                                 [--until-id <event id> | --until-time <time>] [--archive <location>]
+       betterstore-admin export [stream...] [--output <path>] [--server <url>]
+       betterstore-admin import <path> [--format esdb] [--keep-timestamps] [--drop-metadata] [--server <url>]
+
+check    checks every chunk in <data dir>/chunks (the current directory by default) without
+         starting the server, and prints what's in each
+  --key-file    key file the chunks were encrypted with, to count their events
+  --archive     where the server moves full chunks to, as in BETTERSTORE_ARCHIVE, to check those too
+  --repair      cut torn writes off the end of chunks, as the server does when starting
+  --quarantine  move corrupt chunks to <data dir>/quarantine so the server can start without them
+
+backup   has the running server (http://127.0.0.1:50051 by default) copy its store to <target dir>,
+         a directory on the server's machine that has to be empty
+  --since  an earlier backup to only copy what changed since, making an incremental backup
+         Streams shredded later can still be read in the backup, it keeps the key they had
+
+restore  checks a full backup and the incremental backups taken on top of it, oldest first, and
+         copies them into <data dir>, which must not have chunks yet.  The data directory of a
+         stopped server can be restored from as well, to restore it as of an earlier point
+  --key-file         key file the backup's chunks were encrypted with
+  --stream-keys-dir  where to put the backup's stream keys, <data dir>/stream_keys by default
+  --until-id         restore as of the append that wrote this event id, leaving out later appends
+  --until-time       restore as of this time, unix seconds or RFC 3339
+  --archive          the stopped server's archive, as in BETTERSTORE_ARCHIVE, when restoring from
+                     its data directory
+
+export   writes the streams' events as JSON Lines, every stream but the system streams when none
+         are given, to standard output unless --output is given
+import   appends the events of an export, - for standard input, at the revisions they had, to
+         streams that don't have events yet, then checks every stream has as many as were imported
+  --format           esdb for events dumped from EventStoreDB as JSON
+  --keep-timestamps  keep when the events happened rather than giving them the time of import
+  --drop-metadata    import events without their metadata";
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:50051";
+
+// Anything else given on its own is the data directory to check.
+const COMMANDS: [&str; 5] = ["backup", "restore", "export", "import", "check"];
+
+#[derive(Default)]
+struct Options {
+    key_file        : Option<String>,
+    stream_keys_dir : Option<String>,
+    server          : Option<String>,
+    archive         : Option<String>,
+    since_dir       : Option<String>,
+    until           : Option<RestorePoint>,
+    output          : Option<String>,
+    format          : Option<String>,
+    repair          : bool,
+    quarantine      : bool,
+    keep_timestamps : bool,
+    drop_metadata   : bool
+}
+
+fn main() {
+    let mut options    = Options::default();
+    let mut positional = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key-file"        => options.key_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--stream-keys-dir" => options.stream_keys_dir = Some(args.next().unwrap_or_else(|| usage())),
+            "--server"          => options.server = Some(args.next().unwrap_or_else(|| usage())),
+            "--archive"         => options.archive = Some(args.next().unwrap_or_else(|| usage())),
+            "--since"           => options.since_dir = Some(args.next().unwrap_or_else(|| usage())),
+            "--until-id"        => options.until = Some(until_id(&args.next().unwrap_or_else(|| usage()))),
+            "--until-time"      => options.until = Some(until_time(&args.next().unwrap_or_else(|| usage()))),
+            "--output"          => options.output = Some(args.next().unwrap_or_else(|| usage())),
+            "--format"          => options.format = Some(args.next().unwrap_or_else(|| usage())),
+            "--keep-timestamps" => options.keep_timestamps = true,
+            "--drop-metadata"   => options.drop_metadata = true,
+            "--repair"          => options.repair = true,
+            "--quarantine"      => options.quarantine = true,
+            "--help" | "-h" => usage(),
+            _ if arg.starts_with("--") => usage(),
+            _ => positional.push(arg)
+        }
+    }
+
+    let positional : Vec<&str> = positional.iter().map(String::as_str).collect();
+    match positional[..] {
+        ["backup", target_dir] => backup(target_dir, &options),
+        ["restore", ref backups @ .., data_dir] if !backups.is_empty() => restore(backups, data_dir, &options),
+        ["export", ref streams @ ..] => export(streams, &options),
+        ["import", path] => import(path, &options),
+        ["check"] | [] => check(".", &options),
+        ["check", data_dir] | [data_dir] if !COMMANDS.contains(&data_dir) => check(data_dir, &options),
+        _ => usage()
+    }
+}
+
+fn backup(target_dir: &str, options: &Options) {
+    let request = BackupRequest {
+        target_dir : target_dir.to_string(),
+        since_dir  : options.since_dir.clone().unwrap_or_default()
+    };
+
+    let result = with_client(options, |mut client| async move {
+        client.backup(request).await.map_err(|status| status.message().to_string())
+    });
+
+    match result {
+        Ok(response) => {
+            let response = response.into_inner();
+            println!(
+                "Backup {}: copied {} chunks, {} bytes, to {}, {} chunks unchanged",
+                response.backup_id, response.chunks_copied, response.bytes_copied, target_dir, response.chunks_unchanged
+            );
+        }
+        Err(error) => {
+            eprintln!("Backup failed: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn export(streams: &[&str], options: &Options) {
+    let mut output : Box<dyn Write> = match &options.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(error) => fail(&format!("Failed to create {}: {}", path, error))
+        },
+        None => Box::new(BufWriter::new(io::stdout()))
+    };
+
+    let result = with_client(options, |mut client| async move {
+        let exported = transfer::export(&mut client, streams, &mut output).await?;
+        output.flush().map_err(|error| error.to_string())?;
+        Ok(exported)
+    });
+
+    match result {
+        // Standard output may be the export itself.
+        Ok(exported) => eprintln!("Exported {} events", exported),
+        Err(error) => fail(&format!("Export failed: {}", error))
+    }
+}
+
+fn import(path: &str, options: &Options) {
+    let input : Box<dyn io::BufRead> = match path {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        _ => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(error) => fail(&format!("Failed to open {}: {}", path, error))
+        }
+    };
+    let events : Box<dyn Iterator<Item = _>> = match options.format.as_deref() {
+        None => Box::new(transfer::read_export(input)),
+        Some("esdb") => Box::new(esdb::read_dump(input)),
+        Some(_) => usage()
+    };
+    let import_options = ImportOptions {
+        // Dumps from elsewhere are imported to keep the history they have.
+        keep_timestamps : options.keep_timestamps,
+        drop_metadata   : options.drop_metadata
+    };
+
+    let result = with_client(options, |mut client| async move {
+        transfer::import(&mut client, events, &import_options).await
+    });
+
+    match result {
+        Ok(imported) => println!("Imported {} events", imported),
+        Err(error) => fail(&format!("Import failed: {}", error))
+    }
+}
+
+// Runs f with a client connected to the server.
+fn with_client<F, R, T>(options: &Options, f: F) -> Result<T, String>
+    where F: FnOnce(EventsClient<Channel>) -> R, R: Future<Output = Result<T, String>> {
+    let server  = options.server.clone().unwrap_or_else(|| DEFAULT_SERVER.to_string());
+    let runtime = Runtime::new().expect("Failed to start tokio runtime.");
+
+    runtime.block_on(async {
+        let client = EventsClient::connect(server).await.map_err(|error| error.to_string())?;
+        f(client).await
+    })
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn restore(backup_dirs: &[&str], data_dir: &str, options: &Options) {
+    let stream_keys_dir = options.stream_keys_dir.clone().unwrap_or_else(|| format!("{}/stream_keys", data_dir));
+
+    match admin::restore(backup_dirs, data_dir, options.key_file.as_deref(), &stream_keys_dir, options.until, archive(options)) {
+        Ok(summary) => println!(
+            "Restored {} chunks with {} streams to {}, the next event id is {}",
+            summary.chunks, summary.streams, data_dir, summary.next_id
+        ),
+        Err(error) => {
+            eprintln!("Restore failed: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn check(data_dir: &str, options: &Options) {
+    let (repair, quarantine) = (options.repair, options.quarantine);
+
+    let reports = match admin::check_chunks(data_dir, options.key_file.as_deref(), archive(options)) {
+        Ok(reports) => reports,
+        Err(error) => {
+            eprintln!("Failed to check {}: {}", data_dir, error);
+            process::exit(2);
+        }
+    };
+
+    // Anything left unfixed makes for a failing exit code, for use in scripts.
+    let mut unfixed = 0;
+    for report in &reports {
+        println!("{}", describe(report));
+
+        for problem in &report.problems {
+            let fixed = match problem {
+                // Chunks are only archived once full and checked, nothing is changed in the archive.
+                _ if report.archived => false,
+                ChunkProblem::TornTail(_) if repair && !report.is_corrupt() => match admin::repair_tail(data_dir, report) {
+                    Ok(()) => {
+                        println!("  repaired, chunk is now {} bytes", report.bytes_used);
+                        true
+                    }
+                    Err(error) => {
+                        println!("  failed to repair: {}", error);
+                        false
+                    }
+                },
+                ChunkProblem::Corrupt(_) if quarantine => match admin::quarantine(data_dir, report.chunk_number) {
+                    Ok(destination) => {
+                        println!("  moved to {}", destination);
+                        true
+                    }
+                    Err(error) => {
+                        println!("  failed to quarantine: {}", error);
+                        false
+                    }
+                },
+                _ => false
+            };
+            if !fixed {
+                unfixed += 1;
+            }
+        }
+    }
+
+    let problems : usize = reports.iter().map(|report| report.problems.len()).sum();
+    println!("{} chunks checked, {} problems, {} left unfixed", reports.len(), problems, unfixed);
+    if unfixed > 0 {
+        process::exit(1);
+    }
+}
+
+fn describe(report: &ChunkReport) -> String {
+    if let [ChunkProblem::Missing] = report.problems[..] {
+        return format!("chunk {}: missing", report.chunk_number);
+    }
+
+    let contents = match (report.events, report.streams) {
+        (Some(events), Some(streams)) => format!("{} events in {} streams", events, streams),
+        _ if report.key_id != 0 => format!("encrypted with key {}", report.key_id),
+        _ => "events unreadable".to_string()
+    };
+
+    let mut line = format!(
+        "chunk {}{}: version {}, {}, {} bytes used, {} free",
+        report.chunk_number, if report.archived { " (archived)" } else { "" },
+        report.version, contents, report.bytes_used, report.bytes_free
+    );
+
+    for problem in &report.problems {
+        line += &match problem {
+            ChunkProblem::Corrupt(error) => format!("\n  corrupt: {}", error),
+            ChunkProblem::BrokenChain => "\n  does not chain on to the chunk before it".to_string(),
+            ChunkProblem::TornTail(bytes) => format!("\n  torn write of {} bytes at the end", bytes),
+            ChunkProblem::Missing => String::new()
+        };
+    }
+    if report.problems.is_empty() {
+        line += ", ok";
+    }
+
+    line
+}
+
+// The archive given with --archive, if any.
+fn archive(options: &Options) -> Option<Arc<dyn Archive>> {
+    let location = options.archive.as_ref()?;
+    match open_archive(location) {
+        Ok(archive) => Some(archive),
+        Err(error) => fail(&format!("Failed to open archive {}: {}", location, error))
+    }
+}
+
+fn until_id(id: &str) -> RestorePoint {
+    match id.parse() {
+        Ok(id) => RestorePoint::EventId(id),
+        Err(_) => usage()
+    }
+}
+
+fn until_time(time: &str) -> RestorePoint {
+    match time.parse() {
+        Ok(timestamp) => RestorePoint::Timestamp(timestamp),
+        Err(_) => match DateTime::parse_from_rfc3339(time) {
+            Ok(time) => RestorePoint::Timestamp(time.timestamp()),
+            Err(_) => usage()
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
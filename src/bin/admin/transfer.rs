@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+use betterstore::api::{BatchAppendRequest, GetStreamInfoRequest, ReadStreamRequest};
+use betterstore::api::events_client::EventsClient;
+
+// Events are imported in batches of at most this many events of one stream.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+// One line of an export, also what other formats are imported through.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedEvent {
+    pub stream    : String,
+    // Revision of the event in its stream.
+    pub revision  : u64,
+    // Global id, its position in $all.
+    pub id        : u64,
+    pub timestamp : i64,
+    #[serde(rename = "type")]
+    pub event_type : String,
+    pub payload   : String,
+    // The event's metadata as the JSON it usually is, or as a string when it isn't JSON.  Null for
+    // events without any.
+    #[serde(default)]
+    pub metadata  : Option<Value>
+}
+
+// Writes the events of streams as JSON Lines, or of every stream through $all when none are given.
+// System streams are left out of $all.  Returns the number of events written.
+pub async fn export(client: &mut EventsClient<Channel>, streams: &[&str], output: &mut dyn Write) -> Result<u64, String> {
+    let read_all = streams.is_empty();
+    let streams  = match read_all {
+        true  => vec!["$all"],
+        false => streams.to_vec()
+    };
+
+    let mut exported  = 0;
+    let mut revisions : HashMap<String, u64> = HashMap::new();
+    for stream in streams {
+        let request = ReadStreamRequest {
+            stream_name     : stream.to_string(),
+            stream_position : 0,
+            start_time      : 0,
+            end_time        : 0,
+            resolve_links   : false
+        };
+        let mut events = client.read_stream(request).await.map_err(|status| status.message().to_string())?.into_inner();
+
+        while let Some(event) = events.message().await.map_err(|status| status.message().to_string())? {
+            // $all is read from the start, so counting events per stream gives their revisions.
+            let revision = revisions.entry(event.stream_name.clone()).or_insert(0);
+            let exported_event = ExportedEvent {
+                stream     : event.stream_name,
+                revision   : *revision,
+                id         : event.stream_position,
+                timestamp  : event.timestamp,
+                event_type : event.event_type,
+                payload    : event.event,
+                metadata   : exported_metadata(event.metadata)
+            };
+            *revision += 1;
+
+            if read_all && exported_event.stream.starts_with('$') {
+                continue;
+            }
+
+            let line = serde_json::to_string(&exported_event).map_err(|error| error.to_string())?;
+            writeln!(output, "{}", line).map_err(|error| error.to_string())?;
+            exported += 1;
+        }
+    }
+
+    Ok(exported)
+}
+
+fn exported_metadata(metadata: String) -> Option<Value> {
+    match metadata.is_empty() {
+        true  => None,
+        false => Some(serde_json::from_str(&metadata).unwrap_or(Value::String(metadata)))
+    }
+}
+
+fn imported_metadata(metadata: Option<Value>) -> String {
+    match metadata {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(metadata)) => metadata,
+        Some(metadata) => metadata.to_string()
+    }
+}
+
+// Reads an export back, one event per line.
+pub fn read_export(input: impl BufRead) -> impl Iterator<Item = Result<ExportedEvent, String>> {
+    input.lines().enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(i, line)| {
+            let line = line.map_err(|error| error.to_string())?;
+            serde_json::from_str(&line).map_err(|error| format!("Line {}: {}", i + 1, error))
+        })
+}
+
+pub struct ImportOptions {
+    // Keep when events happened instead of when they were imported.
+    pub keep_timestamps : bool,
+    // Import events without the metadata they have.
+    pub drop_metadata   : bool
+}
+
+// Appends the events in the order given, each at the revision it had, so importing into a stream
+// that already has other events fails.  Once done, every stream is checked to hold as many events
+// as were imported into it.  Returns the number of events imported.
+pub async fn import(client: &mut EventsClient<Channel>, events: impl Iterator<Item = Result<ExportedEvent, String>>, options: &ImportOptions) -> Result<u64, String> {
+    let (batches, queue) = mpsc::channel(4);
+    let call = client.batch_append(ReceiverStream::new(queue));
+
+    // Batches are sent as they're read, the server appends each as it arrives.
+    let send = async move {
+        let mut batch : Option<BatchAppendRequest> = None;
+        let mut sent  = 0;
+        // Events and last revision of every stream, to check against once imported.
+        let mut streams : HashMap<String, (u64, u64)> = HashMap::new();
+        for event in events {
+            let event = event?;
+
+            let full = batch.as_ref().is_some_and(|batch| batch.stream_name != event.stream || batch.events.len() == IMPORT_BATCH_SIZE);
+            if full {
+                sent += batch.as_ref().unwrap().events.len() as u64;
+                if batches.send(batch.take().unwrap()).await.is_err() {
+                    break;
+                }
+            }
+
+            let stream = streams.entry(event.stream.clone()).or_insert((0, 0));
+            *stream = (stream.0 + 1, event.revision);
+
+            let batch = batch.get_or_insert_with(|| BatchAppendRequest {
+                correlation_id   : format!("{}@{}", event.revision, event.stream),
+                stream_name      : event.stream.clone(),
+                events           : Vec::new(),
+                event_types      : Vec::new(),
+                expected_version : Some(event.revision as i64 - 1),
+                timestamps       : Vec::new(),
+                event_ids        : Vec::new(),
+                metadata         : Vec::new()
+            });
+            batch.events.push(event.payload);
+            batch.event_types.push(event.event_type);
+            if !options.drop_metadata {
+                batch.metadata.push(imported_metadata(event.metadata));
+            }
+            if options.keep_timestamps {
+                batch.timestamps.push(event.timestamp);
+            }
+        }
+
+        if let Some(batch) = batch {
+            sent += batch.events.len() as u64;
+            let _ = batches.send(batch).await;
+        }
+        Ok::<_, String>((sent, streams))
+    };
+
+    let (response, sent) = tokio::join!(call, send);
+    let (sent, streams) = sent?;
+    let results = response.map_err(|status| status.message().to_string())?.into_inner().results;
+
+    // A failed batch doesn't stop the ones after it, but those of the same stream fail along with it
+    // as they no longer find the stream at their expected version.
+    if let Some(failed) = results.iter().find(|result| !result.error.is_empty()) {
+        return Err(format!("Batch starting at {} failed: {}", failed.correlation_id, failed.error));
+    }
+
+    for (stream, (count, last_revision)) in streams {
+        let request = GetStreamInfoRequest { stream_name: stream.clone() };
+        let info    = client.get_stream_info(request).await.map_err(|status| status.message().to_string())?.into_inner();
+        if info.event_count != count || info.last_revision != last_revision {
+            return Err(format!(
+                "{} has {} events up to revision {} after importing {} up to revision {}",
+                stream, info.event_count, info.last_revision, count, last_revision
+            ));
+        }
+    }
+    Ok(sent)
+}
@@ -1,71 +1,36 @@
-use tonic::transport::Endpoint;
-use tonic::Request;
+use futures::StreamExt;
 
-use betterstore::api;
-use api::events_client::EventsClient;
-use api::{AppendToStreamRequest, ReadStreamRequest};
+use betterstore::client::{Client, ClientError, EventData, DEFAULT_CONNECTION_STRING};
 use rand::Rng;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = Endpoint::from_static("http://127.0.0.1:50051");
-    
-    let mut client  = EventsClient::connect(addr).await?;
+    let client = Client::connect(DEFAULT_CONNECTION_STRING).await?;
     let mut rng = rand::thread_rng();
-    let stream_names = vec!["test1", "test2", "test3"];
+    let stream_names = ["test1", "test2", "test3"];
 
     for i in 0..1 {
         let stream_name = stream_names[rng.gen_range(0..3)];
 
         let mut event_vec = vec![];
         for j in 0..10 {
-            event_vec.push( format!("{} remote payload {} {}", stream_name, i, j));
+            event_vec.push(EventData::new("", &format!("{} remote payload {} {}", stream_name, i, j)));
         }
-        let request = Request::new(AppendToStreamRequest{
-            stream_name : stream_name.to_string(),
-            events      : event_vec
-        });
-        let _ = client.append_to_stream(request).await?;
+        client.append(stream_name, event_vec, None).await?;
     }
 
-    let request = Request::new(
-        ReadStreamRequest{
-            stream_name: "test1".to_string(),
-            stream_position: 5
-        }
-    );
-    let mut response = client.read_stream(request).await?.into_inner();
-
-
-    while let Some(res) = response.message().await? {
-        println!("response: {:?}", res);
-    }
+    for stream_name in stream_names {
+        let mut events = client.read(stream_name, 5);
 
-    let request = Request::new(
-        ReadStreamRequest{
-            stream_name: "test2".to_string(),
-            stream_position: 5
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => println!("event: {:?}", event),
+                // Only one of the streams was written to, the others may not exist yet.
+                Err(ClientError::NotFound(error)) => println!("{}", error),
+                Err(error) => return Err(error.into())
+            }
         }
-    );
-    let mut response = client.read_stream(request).await?.into_inner();
-
-
-    while let Some(res) = response.message().await? {
-        println!("response: {:?}", res);
-    }
-
-    let request = Request::new(
-        ReadStreamRequest{
-            stream_name: "test3".to_string(),
-            stream_position: 5
-        }
-    );
-    let mut response = client.read_stream(request).await?.into_inner();
-
-
-    while let Some(res) = response.message().await? {
-        println!("response: {:?}", res);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,9 +1,6 @@
-use tonic::transport::Endpoint;
-use tonic::Request;
+use futures::StreamExt;
 
-use betterstore::api;
-use api::events_client::EventsClient;
-use api::ReadStreamRequest;
+use betterstore::client::{Client, DEFAULT_CONNECTION_STRING};
 
 /* Design Ideas: */
 /* Initialization:      */
@@ -26,40 +23,29 @@ use api::ReadStreamRequest;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let addr = Endpoint::from_static("http://127.0.0.1:50051");
+  let client = Client::connect(DEFAULT_CONNECTION_STRING).await?;
 
-  let mut client  = EventsClient::connect(addr).await?;
+  // Start for beginning, then keep up with whatever is appended.
+  let mut events = client.subscribe("$all", 0);
 
-  // Start for beginning
-  let mut next_pos : u64 = 0;
+  while let Some(event) = events.next().await {
+    let event = event?;
 
-  loop {
-    let request = Request::new(
-      ReadStreamRequest{
-        stream_name: "$all".to_string(),
-        stream_position: next_pos
-      }
-    );
-    let mut response = client.read_stream(request).await?.into_inner();
-    while let Some(res) = response.message().await? {
-
-      let words: Vec<String> = res.event.split_whitespace().map(|s| s.to_string()).collect();
+    let words: Vec<String> = event.payload.split_whitespace().map(|s| s.to_string()).collect();
 
-      println!("words in sentence: {:?}", words);
-      let article_indices = identify_articles(&words);
-      println!("articles: {:?}", article_indices);
+    println!("words in sentence: {:?}", words);
+    let article_indices = identify_articles(&words);
+    println!("articles: {:?}", article_indices);
 
-      let adjective_indices = identify_adjectives(&words);
-      println!("adjectives: {:?}", adjective_indices);
-
-      next_pos = res.stream_position + 1;
-    }
+    let adjective_indices = identify_adjectives(&words);
+    println!("adjectives: {:?}", adjective_indices);
   }
-  //Ok(())
+
+  Ok(())
 }
 
-fn identify_articles(sentence: &Vec<String>) -> Vec<usize> {
-  let articles = vec!["a", "an", "the"]; // list of articles
+fn identify_articles(sentence: &[String]) -> Vec<usize> {
+  let articles = ["a", "an", "the"]; // list of articles
   let mut result = Vec::new();
 
   for (i, word) in sentence.iter().enumerate() {
@@ -68,10 +54,10 @@ fn identify_articles(sentence: &Vec<String>) -> Vec<usize> {
       }
   }
 
-  return result;
+  result
 }
 
-fn identify_adjectives(sentence: &Vec<String>) -> Vec<String> {
+fn identify_adjectives(sentence: &[String]) -> Vec<String> {
   let mut adjectives = Vec::new();
 
   for (index, word) in sentence.iter().enumerate() {
@@ -81,12 +67,10 @@ fn identify_adjectives(sentence: &Vec<String>) -> Vec<String> {
           continue;
       }
 
-      if word.chars().all(|c| c.is_alphabetic()) {
-          if index + 1 < sentence.len() {
-              let next_word = &sentence[index + 1];
-              if next_word.chars().all(|c| c.is_alphabetic()) {
-                  adjectives.push(word.clone());
-              }
+      if word.chars().all(|c| c.is_alphabetic()) && index + 1 < sentence.len() {
+          let next_word = &sentence[index + 1];
+          if next_word.chars().all(|c| c.is_alphabetic()) {
+              adjectives.push(word.clone());
           }
       }
   }
@@ -1,24 +1,133 @@
-use tonic::{Request, Response, Status};
+use std::collections::VecDeque;
+use std::env;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use futures::{Stream, TryStreamExt};
+use tonic::{Request, Response, Status, Streaming};
 use tonic::transport::Server;
 use actix::{Addr, Actor};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
 
-use betterstore::api::{self, ReadStreamRequest, ReadStreamResponse};
-use betterstore::actor::{BetterStoreActor, AppendToStream, ReadStream};
+use betterstore::api::{self, ReadStreamRequest, ReadStreamResponse, SubscribeToStreamRequest};
+use betterstore::api::{ListStreamsRequest, ListStreamsResponse, GetStreamInfoRequest, GetStreamInfoResponse};
+use betterstore::api::{CreateProjectionRequest, ProjectionRequest, ProjectionStatus, ListProjectionsRequest, ListProjectionsResponse};
+use betterstore::actor::BetterStoreActor;
+use betterstore::actor::{CreateProjection, ControlProjection, ProjectionAction, ListProjections};
+use betterstore::actor::projection::ProjectionError;
+use betterstore::actor::engine::{Compression, Archive, open_archive};
+use betterstore::store::{Store, EngineOptions, ReadOptions, ReadError, AppendError, ProposedEvent, RecordedEvent};
 
 use api::events_server::EventsServer;
 use api::events_server::{Events};
-use api::{AppendToStreamRequest, AppendToStreamResponse};
+use api::projections_server::{Projections, ProjectionsServer};
+use api::{AppendToStreamRequest, AppendToStreamsRequest, AppendToStreamResponse};
+use api::{BatchAppendRequest, BatchAppendResponse, BatchAppendResult};
+use api::{SetStreamMetadataRequest, SetStreamMetadataResponse, DeleteStreamRequest, DeleteStreamResponse};
+use api::{ShredStreamRequest, ShredStreamResponse, VerifyRequest, VerifyResponse};
+use api::{ListChunkRootsRequest, ListChunkRootsResponse, GetEventProofRequest, GetEventProofResponse};
+use api::{BackupRequest, BackupResponse};
 
-// Defining a struct for our RPC service
+// Batches of a BatchAppend that may be waiting to be written at once.
+const MAX_BATCHES_IN_FLIGHT: usize = 64;
+
+// The RPC services, events go to the Store and projections to the actor running them.
+#[derive(Clone)]
 pub struct Api {
+  store      : Store,
   actor_addr : Addr<BetterStoreActor>
 }
 
 impl Api {
-  pub fn new(actor_addr : Addr<BetterStoreActor>) -> Self {
-    Self { actor_addr }
+  pub fn new(store: Store, actor_addr : Addr<BetterStoreActor>) -> Self {
+    Self { store, actor_addr }
+  }
+
+  async fn control_projection(&self, request: Request<ProjectionRequest>, action: ProjectionAction)
+    -> Result<Response<ProjectionStatus>, Status> {
+      let request = ControlProjection{
+        name : request.get_ref().name.clone(),
+        action
+      };
+
+      match self.actor_addr.send(request).await {
+        Ok(Ok(status)) => Ok(Response::new(status)),
+        Ok(Err(error)) => Err(projection_error(error)),
+        Err(error) => Err(Status::internal(error.to_string()))
+      }
+    }
+}
+
+// Pairs each event with its type, timestamp, id and metadata, which may all be shorter than
+// events.  A timestamp of 0 is the time of writing and an empty id no id.
+fn proposed_events(events: &[String], event_types: &[String], timestamps: &[i64], event_ids: &[String], metadata: &[String]) -> Vec<ProposedEvent> {
+  events.iter().enumerate().map(|(i, payload)| {
+    ProposedEvent {
+      event_type : event_types.get(i).cloned().unwrap_or_default(),
+      payload    : payload.clone(),
+      timestamp  : timestamps.get(i).copied().filter(|timestamp| *timestamp != 0),
+      event_id   : event_ids.get(i).filter(|event_id| !event_id.is_empty()).cloned(),
+      metadata   : metadata.get(i).cloned().unwrap_or_default()
+    }
+  }).collect()
+}
+
+fn projection_error(error: ProjectionError) -> Status {
+  match error {
+    ProjectionError::NotFound(name) => Status::not_found(format!("Projection {} not found.", name)),
+    ProjectionError::AlreadyExists(name) => Status::already_exists(format!("Projection {} already exists.", name)),
+    ProjectionError::InvalidScript(error) => Status::invalid_argument(error),
+    ProjectionError::Append(error) => Status::internal(error)
+  }
+}
+
+// Waits for a queued batch to be written.
+async fn batch_append_result<F>(correlation_id: String, appended: Result<F, AppendError>) -> BatchAppendResult
+    where F: Future<Output = Result<i64, AppendError>> {
+  let appended = match appended {
+    Ok(append) => append.await,
+    Err(error) => Err(error)
+  };
+
+  let (error, current_version) = match appended {
+    Ok(current_version) => (String::new(), current_version),
+    Err(error) => (error.to_string(), -1)
+  };
+
+  BatchAppendResult {
+    correlation_id,
+    error,
+    current_version
+  }
+}
+
+fn read_stream_response(event: RecordedEvent) -> ReadStreamResponse {
+  ReadStreamResponse {
+    event           : event.payload,
+    stream_position : event.id,
+    timestamp       : event.timestamp,
+    event_type      : event.event_type,
+    stream_name     : event.stream_name,
+    link_id         : event.link_id,
+    revision        : event.revision,
+    metadata        : event.metadata
+  }
+}
+
+// Clients tell a conflict they can resolve by reading the stream again apart from a request that
+// will never succeed, and from a server that can't write at all.
+fn append_error(error: AppendError) -> Status {
+  match error {
+    AppendError::WrongExpectedVersion(error) => Status::failed_precondition(error),
+    AppendError::Rejected(error) => Status::invalid_argument(error),
+    AppendError::Unavailable(error) => Status::unavailable(error),
+    AppendError::Failed(error) => Status::internal(error)
+  }
+}
+
+fn read_error(error: ReadError) -> Status {
+  match error {
+    ReadError::Unavailable(error) => Status::unavailable(error),
+    _ => Status::not_found(error.to_string())
   }
 }
 
@@ -28,56 +137,293 @@ impl Events for Api {
   // AppendToStream
   async fn append_to_stream(&self,  request: Request<AppendToStreamRequest>) 
     -> Result<Response<AppendToStreamResponse>, Status> {
-      let mut events = vec!["".to_string(); request.get_ref().events.len()];
+      let request = request.get_ref();
+      let events  = proposed_events(&request.events, &request.event_types, &[], &request.event_ids, &request.metadata);
+
+      match self.store.append(&request.stream_name, events, None).await {
+        Ok(_) => {
+          let response = AppendToStreamResponse {
+            response : "success".to_string()
+          };
+          Ok(Response::new(response))
+        }
+        Err(error) => Err(append_error(error))
+      }
+  }
 
-      // This is to setup ownership lifetime, both vectors must be equal size.
-      events.clone_from_slice(&request.get_ref().events);
+  // AppendToStreams
+  async fn append_to_streams(&self,  request: Request<AppendToStreamsRequest>)
+    -> Result<Response<AppendToStreamResponse>, Status> {
+      let streams = request.get_ref().streams.iter().map(|stream| {
+        (stream.stream_name.clone(), proposed_events(&stream.events, &stream.event_types, &[], &stream.event_ids, &stream.metadata))
+      }).collect();
 
-      let request = AppendToStream{
-        stream_name : request.get_ref().stream_name.clone(),
-        events      : events
-      };
-      let _ = self.actor_addr.send(request).await;
+      match self.store.append_to_streams(streams).await {
+        Ok(()) => {
+          let response = AppendToStreamResponse {
+            response : "success".to_string()
+          };
+          Ok(Response::new(response))
+        }
+        Err(error) => Err(append_error(error))
+      }
+  }
 
-      let response = AppendToStreamResponse {
-        response : "success".to_string()
-      };
-      Ok(Response::new(response))
+  // BatchAppend
+  async fn batch_append(&self, request: Request<Streaming<BatchAppendRequest>>)
+    -> Result<Response<BatchAppendResponse>, Status> {
+      let mut batches   = request.into_inner();
+      let mut results   = Vec::new();
+      let mut in_flight = VecDeque::new();
+
+      // Each batch is queued as soon as it arrives, without waiting for the ones before it to be
+      // written, so the writer syncs them together.  They're written in the order they arrived, and
+      // a failed batch doesn't stop the ones after it.
+      while let Some(batch) = batches.message().await? {
+        if in_flight.len() == MAX_BATCHES_IN_FLIGHT {
+          let (correlation_id, appended) = in_flight.pop_front().unwrap();
+          results.push(batch_append_result(correlation_id, appended).await);
+        }
+
+        let events   = proposed_events(&batch.events, &batch.event_types, &batch.timestamps, &batch.event_ids, &batch.metadata);
+        let appended = self.store.queue_append(&batch.stream_name, events, batch.expected_version).await;
+        in_flight.push_back((batch.correlation_id, appended));
+      }
+
+      for (correlation_id, appended) in in_flight {
+        results.push(batch_append_result(correlation_id, appended).await);
+      }
+
+      Ok(Response::new(BatchAppendResponse { results }))
   }
 
   // ReadStream
-  type ReadStreamStream = ReceiverStream<Result<ReadStreamResponse, Status>>;
+  type ReadStreamStream = Pin<Box<dyn Stream<Item = Result<ReadStreamResponse, Status>> + Send>>;
 
   async fn read_stream(&self, request: Request<ReadStreamRequest>)
     -> Result<Response<Self::ReadStreamStream>, Status> {
-      let ( tx, rx) = mpsc::channel(4);
+      let request = request.get_ref();
+      let options = ReadOptions {
+        from_revision : request.stream_position,
+        start_time    : request.start_time,
+        end_time      : request.end_time,
+        resolve_links : request.resolve_links
+      };
+
+      match self.store.read_with(&request.stream_name, options) {
+        Ok(events) => Ok(Response::new(Box::pin(events.map_ok(read_stream_response).map_err(read_error)))),
+        Err(error) => Err(read_error(error))
+      }
+    }
+
+  // SubscribeToStream
+  type SubscribeToStreamStream = Pin<Box<dyn Stream<Item = Result<ReadStreamResponse, Status>> + Send>>;
+
+  async fn subscribe_to_stream(&self, request: Request<SubscribeToStreamRequest>)
+    -> Result<Response<Self::SubscribeToStreamStream>, Status> {
+      let events = self.store.subscribe(&request.get_ref().stream_name, request.get_ref().from_revision);
+      Ok(Response::new(Box::pin(events.map_ok(read_stream_response).map_err(read_error))))
+    }
 
-      let response = ReadStream{
-        stream_name : request.get_ref().stream_name.clone(),
-        stream_position : request.get_ref().stream_position,
-        tx_channel  : tx
+  // ListStreams
+  async fn list_streams(&self, request: Request<ListStreamsRequest>)
+    -> Result<Response<ListStreamsResponse>, Status> {
+      let request = request.get_ref();
+      Ok(Response::new(self.store.list_streams(&request.prefix, &request.page_token, request.page_size)))
+    }
+
+  // GetStreamInfo
+  async fn get_stream_info(&self, request: Request<GetStreamInfoRequest>)
+    -> Result<Response<GetStreamInfoResponse>, Status> {
+      match self.store.stream_info(&request.get_ref().stream_name) {
+        Ok(response) => Ok(Response::new(response)),
+        Err(error) => Err(read_error(error))
+      }
+    }
+
+  // SetStreamMetadata
+  async fn set_stream_metadata(&self, request: Request<SetStreamMetadataRequest>)
+    -> Result<Response<SetStreamMetadataResponse>, Status> {
+      let request = request.get_ref();
+      match self.store.set_stream_metadata(&request.stream_name, &request.metadata).await {
+        Ok(()) => Ok(Response::new(SetStreamMetadataResponse {})),
+        Err(error) => Err(append_error(error))
+      }
+    }
+
+  // DeleteStream
+  async fn delete_stream(&self, request: Request<DeleteStreamRequest>)
+    -> Result<Response<DeleteStreamResponse>, Status> {
+      match self.store.delete_stream(&request.get_ref().stream_name).await {
+        Ok(()) => Ok(Response::new(DeleteStreamResponse {})),
+        Err(error) => Err(append_error(error))
+      }
+    }
+
+  // ShredStream
+  async fn shred_stream(&self, request: Request<ShredStreamRequest>)
+    -> Result<Response<ShredStreamResponse>, Status> {
+      match self.store.shred_stream(&request.get_ref().stream_name) {
+        Ok(()) => Ok(Response::new(ShredStreamResponse {})),
+        Err(error) => Err(Status::failed_precondition(error))
+      }
+    }
+
+  // Verify
+  async fn verify(&self, _request: Request<VerifyRequest>)
+    -> Result<Response<VerifyResponse>, Status> {
+      match self.store.verify().await {
+        Ok(response) => Ok(Response::new(response)),
+        Err(error) => Err(Status::internal(error))
+      }
+    }
+
+  // ListChunkRoots
+  async fn list_chunk_roots(&self, _request: Request<ListChunkRootsRequest>)
+    -> Result<Response<ListChunkRootsResponse>, Status> {
+      match self.store.list_chunk_roots().await {
+        Ok(response) => Ok(Response::new(response)),
+        Err(error) => Err(Status::internal(error))
+      }
+    }
+
+  // Backup
+  async fn backup(&self, request: Request<BackupRequest>)
+    -> Result<Response<BackupResponse>, Status> {
+      let since_dir = match request.get_ref().since_dir.as_str() {
+        "" => None,
+        since_dir => Some(since_dir)
       };
 
-      let _ = self.actor_addr.send(response).await;
+      match self.store.backup(&request.get_ref().target_dir, since_dir).await {
+        Ok(response) => Ok(Response::new(response)),
+        Err(error) => Err(Status::failed_precondition(error))
+      }
+    }
 
-      Ok(Response::new(ReceiverStream::new(rx)))
+  // GetEventProof
+  async fn get_event_proof(&self, request: Request<GetEventProofRequest>)
+    -> Result<Response<GetEventProofResponse>, Status> {
+      match self.store.event_proof(&request.get_ref().stream_name, request.get_ref().revision).await {
+        Ok(response) => Ok(Response::new(response)),
+        Err(error) => Err(read_error(error))
+      }
     }
 }
 
 
+#[tonic::async_trait]
+impl Projections for Api {
+
+  // CreateProjection
+  async fn create_projection(&self, request: Request<CreateProjectionRequest>)
+    -> Result<Response<ProjectionStatus>, Status> {
+      let request = CreateProjection{
+        name          : request.get_ref().name.clone(),
+        source_stream : request.get_ref().source_stream.clone(),
+        script        : request.get_ref().script.clone()
+      };
+
+      match self.actor_addr.send(request).await {
+        Ok(Ok(status)) => Ok(Response::new(status)),
+        Ok(Err(error)) => Err(projection_error(error)),
+        Err(error) => Err(Status::internal(error.to_string()))
+      }
+    }
+
+  // StartProjection
+  async fn start_projection(&self, request: Request<ProjectionRequest>)
+    -> Result<Response<ProjectionStatus>, Status> {
+      self.control_projection(request, ProjectionAction::Start).await
+    }
+
+  // StopProjection
+  async fn stop_projection(&self, request: Request<ProjectionRequest>)
+    -> Result<Response<ProjectionStatus>, Status> {
+      self.control_projection(request, ProjectionAction::Stop).await
+    }
+
+  // ResetProjection
+  async fn reset_projection(&self, request: Request<ProjectionRequest>)
+    -> Result<Response<ProjectionStatus>, Status> {
+      self.control_projection(request, ProjectionAction::Reset).await
+    }
+
+  // GetProjectionStatus
+  async fn get_projection_status(&self, request: Request<ProjectionRequest>)
+    -> Result<Response<ProjectionStatus>, Status> {
+      self.control_projection(request, ProjectionAction::Status).await
+    }
+
+  // ListProjections
+  async fn list_projections(&self, _request: Request<ListProjectionsRequest>)
+    -> Result<Response<ListProjectionsResponse>, Status> {
+      match self.actor_addr.send(ListProjections{}).await {
+        Ok(Ok(projections)) => Ok(Response::new(ListProjectionsResponse { projections })),
+        Ok(Err(())) => Err(Status::internal("Failed to list projections.")),
+        Err(error) => Err(Status::internal(error.to_string()))
+      }
+    }
+}
+
+// BETTERSTORE_ARCHIVE is where full chunks are moved to, a directory or s3://<bucket>/<prefix>,
+// see open_archive for the settings S3 takes.
+fn archive() -> Result<Option<Arc<dyn Archive>>, Box<dyn std::error::Error>> {
+    match env::var("BETTERSTORE_ARCHIVE") {
+        Ok(archive) => Ok(Some(open_archive(&archive)?)),
+        Err(_) => Ok(None)
+    }
+}
+
 #[actix::main] 
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "0.0.0.0:50051".parse()?;
+    // BETTERSTORE_LISTEN is the address to serve on, 0.0.0.0:50051 by default.
+    let addr = env::var("BETTERSTORE_LISTEN").unwrap_or_else(|_| "0.0.0.0:50051".to_string()).parse()?;
+
+    // BETTERSTORE_MAX_EVENT_SIZE sets the largest payload in bytes an event may have,
+    // BETTERSTORE_COMPRESSION (none, zstd or lz4) how new records are compressed,
+    // BETTERSTORE_EVENT_ID_WINDOW how many of the latest event ids are kept to tell retries by,
+    // BETTERSTORE_KEY_FILE the keys chunks are encrypted with and BETTERSTORE_STREAM_KEYS_DIR
+    // where per-stream keys are kept so streams can be shredded.  With an archive,
+    // BETTERSTORE_KEEP_LOCAL_CHUNKS is how many of the latest chunks stay on local disk and
+    // BETTERSTORE_CHUNK_CACHE_SIZE how many archived chunks are kept once read.
+    let mut options = EngineOptions::default();
+    if let Ok(max_event_size) = env::var("BETTERSTORE_MAX_EVENT_SIZE") {
+        options.max_event_size = max_event_size.parse()?;
+    }
+    if let Ok(event_id_window) = env::var("BETTERSTORE_EVENT_ID_WINDOW") {
+        options.event_id_window = event_id_window.parse()?;
+    }
+    if let Ok(compression) = env::var("BETTERSTORE_COMPRESSION") {
+        options.compression = match compression.as_str() {
+            "none" => Compression::None,
+            "zstd" => Compression::Zstd,
+            "lz4"  => Compression::Lz4,
+            _ => return Err(format!("Unknown compression {}.", compression).into())
+        };
+    }
+    options.key_file = env::var("BETTERSTORE_KEY_FILE").ok();
+    options.stream_keys_dir = env::var("BETTERSTORE_STREAM_KEYS_DIR").ok();
+    options.archive = archive()?;
+    if let Ok(keep_local_chunks) = env::var("BETTERSTORE_KEEP_LOCAL_CHUNKS") {
+        options.keep_local_chunks = keep_local_chunks.parse()?;
+    }
+    if let Ok(chunk_cache_size) = env::var("BETTERSTORE_CHUNK_CACHE_SIZE") {
+        options.chunk_cache_size = chunk_cache_size.parse()?;
+    }
 
     println!("Starting betterstore server...");
 
     // Our RPC API
-    let better_store_actor = BetterStoreActor::new().start();
-    let api = Api{actor_addr: better_store_actor};
+    let store = Store::open(options);
+    let better_store_actor = BetterStoreActor::with_store(store.clone()).start();
+    let api = Api::new(store, better_store_actor);
+    let projections_api = api.clone();
 
     // Start RPC server defined in server.rs
     Server::builder()
         .add_service(EventsServer::new(api))
+        .add_service(ProjectionsServer::new(projections_api))
         .serve(addr)
         .await?;
 
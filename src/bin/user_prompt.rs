@@ -1,15 +1,11 @@
 use std::io::{self, Write};
 use chrono::prelude::*;
-use tonic::transport::Endpoint;
-use tonic::Request;
-use betterstore::api;
-use api::events_client::EventsClient;
-use api::{AppendToStreamRequest, ReadStreamRequest};
+use futures::StreamExt;
+use betterstore::client::{Client, ClientError, EventData, DEFAULT_CONNECTION_STRING};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let addr = Endpoint::from_static("http://127.0.0.1:50051");
-  let mut client  = EventsClient::connect(addr).await?;
+  let client = Client::connect(DEFAULT_CONNECTION_STRING).await?;
   let now = Local::now();
   let stream_name = format!("Conversation on {} at {}.", now.format("%Y-%m-%d"), now.format("%H:%M:%S"));
 
@@ -22,11 +18,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match io::stdin().read_line(&mut input) {
       Ok(0) => break,
       Ok(_) => {
-        let request = Request::new(AppendToStreamRequest{
-          stream_name : stream_name.to_string(),
-          events      : vec![input.trim().to_string()]
-        });
-        let _ = client.append_to_stream(request).await?;
+        client.append(&stream_name, vec![EventData::new("UserInput", input.trim())], None).await?;
       }
       Err(error) => {
         eprintln!("Error reading line: {}", error);
@@ -37,15 +29,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   println!("\n\nHere is what we talked about:");
 
-  let request = Request::new(
-    ReadStreamRequest{
-        stream_name: stream_name.to_string(),
-        stream_position: 0
+  let mut events = client.read(&stream_name, 0);
+  while let Some(event) = events.next().await {
+    match event {
+      Ok(event) => println!("{:?}", event),
+      // The conversation stream doesn't exist if nothing was entered.
+      Err(ClientError::NotFound(_)) => break,
+      Err(error) => return Err(error.into())
     }
-  );
-  let mut response = client.read_stream(request).await?.into_inner();
-  while let Some(res) = response.message().await? {
-    println!("{:?}", res);
   }
     Ok(())
 }
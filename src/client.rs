@@ -0,0 +1,426 @@
+//! A client for the betterstore server.
+//!
+//! ```no_run
+//! use betterstore::client::{Client, EventData};
+//! use futures::StreamExt;
+//! use serde::{Serialize, Deserialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Placed { order_id: u64 }
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let client = Client::connect("betterstore://localhost:50051?max_retries=5").await?;
+//! client.append("orders", vec![EventData::json("Placed", &Placed { order_id: 1 })?], Some(-1)).await?;
+//!
+//! let mut orders = client.subscribe("orders", 0);
+//! while let Some(event) = orders.next().await {
+//!   let placed : Placed = event?.json()?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::stream::{self, Stream};
+use serde::Serialize;
+use tonic::{Code, Response, Status, Streaming};
+use tonic::transport::{Channel, Endpoint};
+
+use crate::api::events_client::EventsClient;
+use crate::api::{BatchAppendRequest, ReadStreamRequest, ReadStreamResponse, SubscribeToStreamRequest};
+
+pub use crate::actor::engine::event::RecordedEvent;
+
+/// Where the server listens unless it's told otherwise.
+pub const DEFAULT_CONNECTION_STRING: &str = "betterstore://127.0.0.1:50051";
+
+const DEFAULT_PORT: u16 = 50051;
+
+/// Events read from the server, or why reading them stopped.
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<RecordedEvent, ClientError>> + Send>>;
+
+#[derive(Debug)]
+pub enum ClientError {
+  InvalidConnectionString(String),
+  Connect(String),
+  /// The stream, or the revision asked for, doesn't exist.
+  NotFound(String),
+  /// The server refused to append the events, such as when the stream isn't at the expected
+  /// version.  Never retried.
+  Rejected(String),
+  Json(serde_json::Error),
+  /// Any other failure, including a connection that was still down after the last retry.
+  Status(Box<Status>)
+}
+
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ClientError::InvalidConnectionString(error) => write!(f, "Invalid connection string {}", error),
+      ClientError::Connect(error) => write!(f, "Failed to connect: {}", error),
+      ClientError::NotFound(error) | ClientError::Rejected(error) => write!(f, "{}", error),
+      ClientError::Json(error) => write!(f, "{}", error),
+      ClientError::Status(status) => write!(f, "{:?}: {}", status.code(), status.message())
+    }
+  }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<serde_json::Error> for ClientError {
+  fn from(error: serde_json::Error) -> Self {
+    ClientError::Json(error)
+  }
+}
+
+impl From<Status> for ClientError {
+  fn from(status: Status) -> Self {
+    match status.code() {
+      Code::NotFound => ClientError::NotFound(status.message().to_string()),
+      _ => ClientError::Status(Box::new(status))
+    }
+  }
+}
+
+/// How to reach the server and how hard to try, usually parsed from a connection string:
+///
+///   betterstore://<host>[:<port>][?max_retries=<n>&retry_delay_ms=<ms>&timeout_ms=<ms>]
+///
+/// The port is 50051 unless given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+  /// http://<host>:<port> of the server.
+  pub endpoint    : String,
+  /// Times a request that failed for want of a connection is tried again, 0 for never.  For
+  /// subscriptions it's the times in a row reconnecting may fail.
+  pub max_retries : u32,
+  /// Wait before the first retry, each retry after it waiting that much longer again.
+  pub retry_delay : Duration,
+  /// Longest to wait for the server to answer a request.  Reads and subscriptions only wait this
+  /// long for their first answer, not for every event.
+  pub timeout     : Option<Duration>
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    Self {
+      endpoint    : format!("http://127.0.0.1:{}", DEFAULT_PORT),
+      max_retries : 3,
+      retry_delay : Duration::from_millis(200),
+      timeout     : None
+    }
+  }
+}
+
+impl ClientOptions {
+  fn backoff(&self, attempt: u32) -> Duration {
+    self.retry_delay * attempt
+  }
+}
+
+impl FromStr for ClientOptions {
+  type Err = ClientError;
+
+  fn from_str(connection_string: &str) -> Result<Self, ClientError> {
+    let invalid = |reason: &str| ClientError::InvalidConnectionString(format!("{}: {}", connection_string, reason));
+
+    let rest = match connection_string.split_once("://") {
+      Some(("betterstore" | "http", rest)) => rest,
+      Some((scheme, _)) => return Err(invalid(&format!("unknown scheme {}", scheme))),
+      None => return Err(invalid("it doesn't start with betterstore://"))
+    };
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let address = address.trim_end_matches('/');
+    if address.is_empty() || address.contains('/') {
+      return Err(invalid("expected a host and port only"));
+    }
+
+    // IPv6 addresses come in brackets, whose colons aren't the port's.
+    let endpoint = match address.rsplit_once(':') {
+      Some((_, port)) if !port.ends_with(']') => match port.parse::<u16>() {
+        Ok(_) => format!("http://{}", address),
+        Err(_) => return Err(invalid(&format!("port {} isn't a number", port)))
+      },
+      _ => format!("http://{}:{}", address, DEFAULT_PORT)
+    };
+
+    let mut options = ClientOptions { endpoint, ..ClientOptions::default() };
+    for setting in query.split('&').filter(|setting| !setting.is_empty()) {
+      let (name, value) = setting.split_once('=').unwrap_or((setting, ""));
+      let number = value.parse::<u64>().map_err(|_| invalid(&format!("{} isn't a number", setting)))?;
+      match name {
+        "max_retries"    => options.max_retries = u32::try_from(number).map_err(|_| invalid(&format!("{} is too large", setting)))?,
+        "retry_delay_ms" => options.retry_delay = Duration::from_millis(number),
+        "timeout_ms"     => options.timeout = Some(Duration::from_millis(number)),
+        _ => return Err(invalid(&format!("unknown setting {}", name)))
+      }
+    }
+
+    Ok(options)
+  }
+}
+
+/// An event to append.  Every event gets an id, so appending it again after a lost connection
+/// doesn't write it twice.
+#[derive(Debug, Clone)]
+pub struct EventData {
+  pub id         : String,
+  pub event_type : String,
+  pub payload    : String,
+  /// Empty for none.
+  pub metadata   : String
+}
+
+impl EventData {
+  /// An event whose payload is the text given, with a random id.
+  pub fn new(event_type: &str, payload: &str) -> Self {
+    Self {
+      id         : format!("{:032x}", rand::random::<u128>()),
+      event_type : event_type.to_string(),
+      payload    : payload.to_string(),
+      metadata   : String::new()
+    }
+  }
+
+  /// An event whose payload is data as JSON, with a random id.
+  pub fn json<T: Serialize>(event_type: &str, data: &T) -> Result<Self, ClientError> {
+    Ok(Self::new(event_type, &serde_json::to_string(data)?))
+  }
+
+  /// Replaces the random id with one of the caller's, such as one derived from the request the
+  /// event records, so that appends made again after the client itself gave up are retries too.
+  /// Ids are unique across the store.
+  pub fn with_id(self, id: &str) -> Self {
+    Self { id: id.to_string(), ..self }
+  }
+
+  /// Gives the event metadata, read back along with it.
+  pub fn with_metadata(self, metadata: &str) -> Self {
+    Self { metadata: metadata.to_string(), ..self }
+  }
+}
+
+/// A connection to the server.  Clones share the connection, which is made again whenever it's
+/// lost.
+#[derive(Clone)]
+pub struct Client {
+  events  : EventsClient<Channel>,
+  options : ClientOptions
+}
+
+impl Client {
+  pub async fn connect(connection_string: &str) -> Result<Self, ClientError> {
+    Self::connect_with(connection_string.parse()?).await
+  }
+
+  pub async fn connect_with(options: ClientOptions) -> Result<Self, ClientError> {
+    let mut endpoint = Endpoint::from_shared(options.endpoint.clone())
+      .map_err(|error| ClientError::InvalidConnectionString(format!("{}: {}", options.endpoint, error)))?;
+    if let Some(timeout) = options.timeout {
+      endpoint = endpoint.timeout(timeout);
+    }
+
+    let mut attempt = 0;
+    let channel = loop {
+      match endpoint.connect().await {
+        Ok(channel) => break channel,
+        Err(_) if attempt < options.max_retries => {
+          attempt += 1;
+          tokio::time::sleep(options.backoff(attempt)).await;
+        }
+        Err(error) => return Err(ClientError::Connect(format!("{}: {}", options.endpoint, error)))
+      }
+    };
+
+    Ok(Self {
+      events : EventsClient::new(channel),
+      options
+    })
+  }
+
+  /// Appends events to a stream in one transaction, returning the stream's last revision after it.
+  /// With expected_version the append is refused unless the stream's last revision is that (-1
+  /// for a stream that doesn't exist yet).  An append retried after it was written returns the
+  /// stream's last revision as it is by then.
+  pub async fn append(&self, stream_name: &str, events: Vec<EventData>, expected_version: Option<i64>) -> Result<i64, ClientError> {
+    let request = BatchAppendRequest {
+      correlation_id   : String::new(),
+      stream_name      : stream_name.to_string(),
+      events           : events.iter().map(|event| event.payload.clone()).collect(),
+      event_types      : events.iter().map(|event| event.event_type.clone()).collect(),
+      expected_version,
+      timestamps       : Vec::new(),
+      metadata         : events.iter().map(|event| event.metadata.clone()).collect(),
+      event_ids        : events.into_iter().map(|event| event.id).collect()
+    };
+
+    let response = self.retry(|mut events| {
+      let request = request.clone();
+      async move { events.batch_append(stream::iter(vec![request])).await }
+    }).await?;
+
+    match response.results.into_iter().next() {
+      Some(result) if result.error.is_empty() => Ok(result.current_version),
+      Some(result) => Err(ClientError::Rejected(result.error)),
+      None => Err(ClientError::Status(Box::new(Status::internal("The server didn't answer the append."))))
+    }
+  }
+
+  /// Reads a stream from a revision on, up to its last event.  A read cut short by a lost
+  /// connection carries on after the last event it returned.
+  pub fn read(&self, stream_name: &str, from_revision: u64) -> EventStream {
+    self.follow(stream_name, from_revision, false)
+  }
+
+  /// Reads a stream from a revision on and then waits for more events, reconnecting when the
+  /// connection is lost and carrying on after the last event it returned.  A stream that doesn't
+  /// exist yet is waited for.  Links come as links.
+  pub fn subscribe(&self, stream_name: &str, from_revision: u64) -> EventStream {
+    self.follow(stream_name, from_revision, true)
+  }
+
+  fn follow(&self, stream_name: &str, from_revision: u64, live: bool) -> EventStream {
+    let follow = Follow {
+      client        : self.clone(),
+      stream_name   : stream_name.to_string(),
+      next_revision : from_revision,
+      live,
+      responses     : None,
+      attempt       : 0,
+      done          : false
+    };
+
+    Box::pin(stream::unfold(follow, |mut follow| async move {
+      if follow.done {
+        return None;
+      }
+
+      loop {
+        if follow.responses.is_none() {
+          match follow.open().await {
+            Ok(responses) => follow.responses = Some(responses),
+            Err(status) => match follow.retry_or_fail(status).await {
+              Some(error) => return Some((Err(error), follow)),
+              None => continue
+            }
+          }
+        }
+
+        let status = match follow.responses.as_mut().unwrap().message().await {
+          Ok(Some(response)) => {
+            follow.attempt       = 0;
+            // Events come one after the other from next_revision on, so this is where the event
+            // is in the stream read, also for $all and the other streams of events written elsewhere.
+            follow.next_revision += 1;
+            return Some((Ok(response.into()), follow));
+          }
+          Ok(None) if !follow.live => return None,
+          // Subscriptions only end when the server goes away.
+          Ok(None) => Status::unavailable("The subscription ended."),
+          Err(status) => status
+        };
+
+        follow.responses = None;
+        if let Some(error) = follow.retry_or_fail(status).await {
+          return Some((Err(error), follow));
+        }
+      }
+    }))
+  }
+
+  // Makes the call until it succeeds, fails for a reason other than the connection, or is out of
+  // retries.
+  async fn retry<T, F, R>(&self, mut call: F) -> Result<T, ClientError>
+      where F: FnMut(EventsClient<Channel>) -> R, R: Future<Output = Result<Response<T>, Status>> {
+    let mut attempt = 0;
+    loop {
+      match call(self.events.clone()).await {
+        Ok(response) => return Ok(response.into_inner()),
+        Err(status) if is_transient(&status) && attempt < self.options.max_retries => {
+          attempt += 1;
+          tokio::time::sleep(self.options.backoff(attempt)).await;
+        }
+        Err(status) => return Err(status.into())
+      }
+    }
+  }
+}
+
+// A read or subscription, kept as events are taken from it.
+struct Follow {
+  client        : Client,
+  stream_name   : String,
+  // Revision to start from when the read has to be started again.
+  next_revision : u64,
+  live          : bool,
+  responses     : Option<Streaming<ReadStreamResponse>>,
+  // Failed attempts since the last event.
+  attempt       : u32,
+  done          : bool
+}
+
+impl Follow {
+  // Doesn't borrow the Follow while waiting, the responses it may hold can't be shared between threads.
+  fn open(&self) -> impl Future<Output = Result<Streaming<ReadStreamResponse>, Status>> {
+    let mut events    = self.client.events.clone();
+    let stream_name   = self.stream_name.clone();
+    let next_revision = self.next_revision;
+    let live          = self.live;
+
+    async move {
+      let response = match live {
+        true => events.subscribe_to_stream(SubscribeToStreamRequest {
+          stream_name,
+          from_revision : next_revision
+        }).await?,
+        false => events.read_stream(ReadStreamRequest {
+          stream_name,
+          stream_position : next_revision,
+          start_time      : 0,
+          end_time        : 0,
+          resolve_links   : false
+        }).await?
+      };
+
+      Ok(response.into_inner())
+    }
+  }
+
+  // Waits to try again, or gives the error back once there's no point in trying again.
+  async fn retry_or_fail(&mut self, status: Status) -> Option<ClientError> {
+    if is_transient(&status) && self.attempt < self.client.options.max_retries {
+      self.attempt += 1;
+      tokio::time::sleep(self.client.options.backoff(self.attempt)).await;
+      return None;
+    }
+
+    self.done = true;
+    Some(status.into())
+  }
+}
+
+// Failures of the connection rather than answers from the server, which tonic reports as Unknown
+// as well as Unavailable.
+fn is_transient(status: &Status) -> bool {
+  matches!(status.code(), Code::Unavailable | Code::Unknown | Code::Cancelled | Code::DeadlineExceeded)
+}
+
+impl From<ReadStreamResponse> for RecordedEvent {
+  fn from(response: ReadStreamResponse) -> Self {
+    Self {
+      id          : response.stream_position,
+      stream_name : response.stream_name,
+      revision    : response.revision,
+      event_type  : response.event_type,
+      payload     : response.event,
+      timestamp   : response.timestamp,
+      link_id     : response.link_id,
+      metadata    : response.metadata
+    }
+  }
+}
@@ -7,3 +7,5 @@ pub mod api {
 
 // Pull in modules defined in subdirs below
 pub mod actor;
+pub mod store;
+pub mod client;
@@ -0,0 +1,250 @@
+//! Betterstore as a library, without the gRPC server in front of it.
+//!
+//! ```no_run
+//! use betterstore::store::{Store, ProposedEvent};
+//! use futures::StreamExt;
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let store = Store::in_memory();
+//! store.append("orders", vec![ProposedEvent::new("Placed", r#"{"id": 1}"#)], Some(-1)).await?;
+//!
+//! let mut events = store.read("orders", 0)?;
+//! while let Some(event) = events.next().await {
+//!   let event = event?;
+//!   println!("{} {} {}", event.revision, event.event_type, event.payload);
+//! }
+//!
+//! // Carries on with events appended from now on.
+//! let mut orders = store.subscribe("orders", 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::stream::{self, Stream};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::actor::engine::Engine;
+use crate::actor::engine::event::Event;
+use crate::api::{ListStreamsResponse, GetStreamInfoResponse, VerifyResponse, BackupResponse};
+use crate::api::{ListChunkRootsResponse, GetEventProofResponse};
+
+pub use crate::actor::engine::{AppendError, EngineOptions, ReadError};
+pub use crate::actor::engine::event::{ProposedEvent, RecordedEvent};
+
+// Events read ahead of what a reader has taken so far.
+const READ_BUFFER_SIZE: usize = 4;
+
+// Events a subscription reads from the engine at a time.
+const SUBSCRIBE_PAGE_SIZE: usize = 256;
+
+/// Where to start and stop reading a stream.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+  /// Revision of the first event to read.
+  pub from_revision : u64,
+  /// Unix timestamps in seconds of the first and last events to read, inclusive; 0 leaves that
+  /// end unbounded.
+  pub start_time    : i64,
+  pub end_time      : i64,
+  /// Read link events as the events they point at.
+  pub resolve_links : bool
+}
+
+/// An event store.  Clones share the same store, which stays open until the last of them is
+/// dropped.
+#[derive(Clone)]
+pub struct Store {
+  engine : Arc<Engine>
+}
+
+impl Store {
+  /// Opens the store the options describe, its chunks in ./chunks unless they name other storage.
+  /// Panics if the store can't be read.
+  pub fn open(options: EngineOptions) -> Self {
+    Self::with_engine(Engine::with_options(options))
+  }
+
+  /// A store that lasts as long as the process, writing nothing to disk.
+  pub fn in_memory() -> Self {
+    Self::with_engine(Engine::in_memory())
+  }
+
+  pub fn with_engine(engine: Engine) -> Self {
+    Self { engine: Arc::new(engine) }
+  }
+
+  pub fn engine(&self) -> &Engine {
+    &self.engine
+  }
+
+  /// Appends events to a stream, resolving to the stream's last revision once they are on disk.
+  /// With expected_version the append is refused unless the stream's last revision is that
+  /// (-1 for a stream that doesn't exist yet).
+  pub async fn append(&self, stream_name: &str, events: Vec<ProposedEvent>, expected_version: Option<i64>) -> Result<i64, AppendError> {
+    self.engine.append_expecting(stream_name.to_string(), events, expected_version).await
+  }
+
+  /// Queues an append, resolving once it's queued to a future that resolves like append.  Queued
+  /// appends are written in the order they were queued, so waiting on several at once lets them
+  /// share a sync to disk.
+  pub async fn queue_append(&self, stream_name: &str, events: Vec<ProposedEvent>, expected_version: Option<i64>) -> Result<impl Future<Output = Result<i64, AppendError>>, AppendError> {
+    self.engine.queue_expecting(stream_name.to_string(), events, expected_version).await
+  }
+
+  /// Appends to several streams in one transaction, either all of the events are written or none
+  /// are.
+  pub async fn append_to_streams(&self, streams: Vec<(String, Vec<ProposedEvent>)>) -> Result<(), AppendError> {
+    self.engine.append_to_streams(streams).await
+  }
+
+  /// Reads a stream from a revision on, up to its last event when read.  A chunk that can't be
+  /// read, such as an archived one that couldn't be fetched, ends the read with
+  /// ReadError::Unavailable.
+  pub fn read(&self, stream_name: &str, from_revision: u64) -> Result<impl Stream<Item = Result<RecordedEvent, ReadError>>, ReadError> {
+    self.read_with(stream_name, ReadOptions { from_revision, ..ReadOptions::default() })
+  }
+
+  /// Reads a stream as the options say.  Events are read on a Tokio task, so this has to be called
+  /// from within a Tokio runtime; dropping the returned stream stops the read.
+  pub fn read_with(&self, stream_name: &str, options: ReadOptions) -> Result<impl Stream<Item = Result<RecordedEvent, ReadError>>, ReadError> {
+    let (tx, rx) = mpsc::channel(READ_BUFFER_SIZE);
+    let read = self.engine.read_stream(
+      stream_name.to_string(),
+      options.from_revision,
+      options.start_time,
+      options.end_time,
+      options.resolve_links,
+      tx
+    )?;
+
+    tokio::spawn(read);
+    Ok(ReceiverStream::new(rx))
+  }
+
+  /// Reads a stream from a revision on and then waits for more, only ending after a chunk that
+  /// can't be read, as reads do.  A stream that doesn't exist yet is waited for as well.  Links
+  /// are read as links.
+  pub fn subscribe(&self, stream_name: &str, from_revision: u64) -> impl Stream<Item = Result<RecordedEvent, ReadError>> {
+    let state = Subscription {
+      engine      : self.engine.clone(),
+      stream_name : stream_name.to_string(),
+      revision    : from_revision,
+      appends     : self.engine.watch_appends(),
+      read        : VecDeque::new(),
+      failed      : false
+    };
+
+    stream::unfold(state, |mut subscription| async move {
+      loop {
+        if let Some(event) = subscription.read.pop_front() {
+          return Some((Ok(event), subscription));
+        }
+        if subscription.failed {
+          return None;
+        }
+
+        // Appends flushed after this are caught by changed() below even if the read misses them.
+        subscription.appends.borrow_and_update();
+        let events = match subscription.read_page().await {
+          Ok(events) => events,
+          Err(ReadError::StreamNotFound(_)) => Vec::new(),
+          Err(error) => {
+            subscription.failed = true;
+            return Some((Err(error), subscription));
+          }
+        };
+        if events.is_empty() {
+          // Can't fail, the subscription holds on to the engine that sends the changes.
+          subscription.appends.changed().await.ok()?;
+          continue;
+        }
+
+        // Events come with their revision in the stream they were written to, which for $all and
+        // the other system streams isn't where they are in the stream read.
+        let start = subscription.revision;
+        subscription.revision += events.len() as u64;
+        subscription.read.extend(events.into_iter().zip(start..).map(|((_, event), revision)| RecordedEvent::new(revision, event, None)));
+      }
+    })
+  }
+
+  /// Names of the streams starting with prefix, a page at a time.  page_token is the
+  /// next_page_token of the page before, empty for the first page, and page_size 0 for the
+  /// default of 100.
+  pub fn list_streams(&self, prefix: &str, page_token: &str, page_size: u32) -> ListStreamsResponse {
+    self.engine.list_streams(prefix, page_token, page_size)
+  }
+
+  pub fn stream_info(&self, stream_name: &str) -> Result<GetStreamInfoResponse, ReadError> {
+    self.engine.get_stream_info(stream_name)
+  }
+
+  /// Replaces the stream's metadata, which has to be a JSON document.
+  pub async fn set_stream_metadata(&self, stream_name: &str, metadata: &str) -> Result<(), AppendError> {
+    self.engine.set_stream_metadata(stream_name, metadata).await
+  }
+
+  /// Deletes the stream, which then reads as StreamNotFound and can't be appended to.  Its events
+  /// stay in $all and the other system projections.
+  pub async fn delete_stream(&self, stream_name: &str) -> Result<(), AppendError> {
+    self.engine.delete_stream(stream_name).await
+  }
+
+  /// Destroys the stream's key, leaving its payloads unreadable for good.  Only streams written
+  /// while the store keeps per-stream keys can be shredded.
+  pub fn shred_stream(&self, stream_name: &str) -> Result<(), String> {
+    self.engine.shred_stream(stream_name)
+  }
+
+  /// Checks the hash chain of every chunk.
+  pub async fn verify(&self) -> Result<VerifyResponse, String> {
+    self.engine.verify().await
+  }
+
+  /// Copies the store to the empty target_dir, only what changed since the backup in since_dir
+  /// when given.
+  pub async fn backup(&self, target_dir: &str, since_dir: Option<&str>) -> Result<BackupResponse, String> {
+    self.engine.backup(target_dir.to_string(), since_dir.map(str::to_string)).await
+  }
+
+  /// The Merkle root over the records of every chunk.
+  pub async fn list_chunk_roots(&self) -> Result<ListChunkRootsResponse, String> {
+    self.engine.list_chunk_roots().await
+  }
+
+  /// An event with the hashes leading from its record up to its chunk's Merkle root.
+  pub async fn event_proof(&self, stream_name: &str, revision: u64) -> Result<GetEventProofResponse, ReadError> {
+    self.engine.event_proof(stream_name, revision).await
+  }
+}
+
+struct Subscription {
+  engine      : Arc<Engine>,
+  stream_name : String,
+  // Revision of the next event to read from the engine.
+  revision    : u64,
+  appends     : tokio::sync::watch::Receiver<u64>,
+  // Events read but not yet taken from the subscription.
+  read        : VecDeque<RecordedEvent>,
+  failed      : bool
+}
+
+impl Subscription {
+  // Reads on a blocking thread, fetching an archived chunk can take a while.  Each event comes with
+  // its revision in the stream it was written to.
+  fn read_page(&self) -> impl Future<Output = Result<Vec<(u64, Event)>, ReadError>> {
+    let engine      = self.engine.clone();
+    let stream_name = self.stream_name.clone();
+    let revision    = self.revision;
+
+    async move {
+      tokio::task::spawn_blocking(move || engine.read_events(&stream_name, revision, SUBSCRIBE_PAGE_SIZE)).await
+        .unwrap_or_else(|error| Err(ReadError::Unavailable(error.to_string())))
+    }
+  }
+}
@@ -0,0 +1,76 @@
+// Archived chunks, with the store and its chunk cache in a directory of their own.
+
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use betterstore::actor::engine::{FileStorage, LocalArchive};
+use betterstore::store::{Store, EngineOptions, ProposedEvent, ReadError};
+use futures::StreamExt;
+
+fn open(dir: &str) -> Store {
+  Store::open(EngineOptions {
+    storage           : Some(Arc::new(FileStorage::new(&format!("{}/chunks", dir)).unwrap())),
+    archive           : Some(Arc::new(LocalArchive::new(&format!("{}/archive", dir)).unwrap())),
+    keep_local_chunks : 1,
+    archive_interval  : Duration::from_millis(10),
+    chunk_cache_size  : 1,
+    cache_dir         : format!("{}/chunk_cache", dir),
+    index_dir         : format!("{}/chunk_index", dir),
+    ..EngineOptions::default()
+  })
+}
+
+fn local_chunks(dir: &str) -> usize {
+  std::fs::read_dir(format!("{}/chunks", dir)).unwrap().count()
+}
+
+#[tokio::test]
+async fn archived_chunks_are_not_fetched_to_start_or_back_up() {
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+  let dir = std::env::temp_dir().join(format!("betterstore-archive-{}-{}", std::process::id(), nanos));
+  let dir = dir.to_str().unwrap().to_string();
+  let archive_dir = format!("{}/archive", dir);
+
+  // Four chunks, all but the last archived once the archiver has been round.
+  let store   = open(&dir);
+  let payload = "x".repeat(100_000);
+  for _ in 0 .. 30 {
+    store.append("a", vec![ProposedEvent::new("Added", &payload)], None).await.unwrap();
+  }
+  let deadline = Instant::now() + Duration::from_secs(5);
+  while local_chunks(&dir) > 1 && Instant::now() < deadline {
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  assert_eq!(local_chunks(&dir), 1);
+
+  // The admin tool sees the archived chunks through the archive.
+  let check = Command::new(env!("CARGO_BIN_EXE_betterstore-admin"))
+    .args(["check", &dir, "--archive", &archive_dir])
+    .output()
+    .unwrap();
+  let report = String::from_utf8_lossy(&check.stdout);
+  assert!(check.status.success(), "{}", report);
+  assert_eq!(report.matches("(archived)").count(), 3, "{}", report);
+
+  let (full, incremental) = (format!("{}/full", dir), format!("{}/incremental", dir));
+  store.backup(&full, None).await.unwrap();
+
+  // From here on nothing can be fetched from the archive.
+  for entry in std::fs::read_dir(&archive_dir).unwrap() {
+    std::fs::write(entry.unwrap().path(), b"unreadable").unwrap();
+  }
+
+  let backup = store.backup(&incremental, Some(&full)).await.unwrap();
+  assert_eq!((backup.chunks_copied, backup.chunks_unchanged), (1, 3));
+  drop(store);
+
+  // The index comes from the summaries, only reading the events needs the archive.
+  let store = open(&dir);
+  assert_eq!(store.stream_info("a").unwrap().last_revision, 29);
+  let mut read = store.read("a", 0).unwrap();
+  assert!(matches!(read.next().await, Some(Err(ReadError::Unavailable(_)))));
+
+  drop(store);
+  std::fs::remove_dir_all(&dir).unwrap();
+}
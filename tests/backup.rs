@@ -0,0 +1,147 @@
+// Backups of a store kept in files, restored as the admin tool does.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use betterstore::actor::engine::FileStorage;
+use betterstore::actor::engine::admin::{self, RestorePoint};
+use betterstore::store::{Store, EngineOptions, ProposedEvent, RecordedEvent};
+use futures::StreamExt;
+
+// Chunks hold 1 MB, so no more than two of these fit in one.
+const LARGE: usize = 400_000;
+
+fn temp_dir(name: &str) -> String {
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+  let dir = std::env::temp_dir().join(format!("betterstore-{}-{}-{}", name, std::process::id(), nanos));
+  std::fs::create_dir_all(&dir).unwrap();
+  dir.to_str().unwrap().to_string()
+}
+
+fn open(data_dir: &str) -> Store {
+  let storage = FileStorage::new(&format!("{}/chunks", data_dir)).unwrap();
+  Store::open(EngineOptions { storage: Some(Arc::new(storage)), ..EngineOptions::default() })
+}
+
+fn large(name: &str) -> ProposedEvent {
+  ProposedEvent::new("Added", &format!("{}:{}", name, ".".repeat(LARGE)))
+}
+
+async fn read_all(store: &Store, stream_name: &str) -> Vec<RecordedEvent> {
+  store.read(stream_name, 0).unwrap().map(|event| event.unwrap()).collect().await
+}
+
+// Payloads without the padding large events carry.
+async fn names(store: &Store) -> Vec<String> {
+  read_all(store, "$all").await.iter()
+    .map(|event| event.payload.split(':').next().unwrap().to_string())
+    .collect()
+}
+
+fn restore(backup_dirs: &[&str], data_dir: &str, until: Option<RestorePoint>) -> Result<admin::RestoreSummary, String> {
+  admin::restore(backup_dirs, data_dir, None, &format!("{}/stream_keys", data_dir), until, None)
+}
+
+#[tokio::test]
+async fn full_backups_restore_every_chunk() {
+  let dir = temp_dir("backup-full");
+  let store = open(&format!("{}/data", dir));
+  for name in ["a", "b", "c"] {
+    store.append("orders", vec![large(name)], None).await.unwrap();
+  }
+  let backup = store.backup(&format!("{}/full", dir), None).await.unwrap();
+  assert_eq!((backup.chunks_copied, backup.chunks_unchanged), (2, 0));
+
+  // Nothing written after the backup is restored.
+  store.append("orders", vec![ProposedEvent::new("Added", "d")], None).await.unwrap();
+  drop(store);
+
+  let restored = format!("{}/restored", dir);
+  let summary  = restore(&[&format!("{}/full", dir)], &restored, None).unwrap();
+  assert_eq!((summary.chunks, summary.streams, summary.next_id), (2, 1, 3));
+  assert_eq!(names(&open(&restored)).await, ["a", "b", "c"]);
+
+  // A data directory that already has chunks isn't written over.
+  assert!(restore(&[&format!("{}/full", dir)], &restored, None).is_err());
+
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn incremental_backups_only_restore_as_a_chain() {
+  let dir = temp_dir("backup-incremental");
+  let backup_dir = |name: &str| format!("{}/{}", dir, name);
+  let store = open(&backup_dir("data"));
+
+  for name in ["a", "b", "c"] {
+    store.append("orders", vec![large(name)], None).await.unwrap();
+  }
+  store.backup(&backup_dir("full"), None).await.unwrap();
+  store.append("orders", vec![large("d")], None).await.unwrap();
+  store.append("orders", vec![large("e")], None).await.unwrap();
+
+  // The first chunk was full in the full backup already, the second filled up since.
+  let incremental = store.backup(&backup_dir("incremental"), Some(&backup_dir("full"))).await.unwrap();
+  assert_eq!((incremental.chunks_copied, incremental.chunks_unchanged), (2, 1));
+  let other = store.backup(&backup_dir("other"), None).await.unwrap();
+  assert_eq!(other.chunks_unchanged, 0);
+  drop(store);
+
+  let (full, incremental, other) = (backup_dir("full"), backup_dir("incremental"), backup_dir("other"));
+  assert!(restore(&[&incremental], &backup_dir("alone"), None).is_err());
+  assert!(restore(&[&other, &incremental], &backup_dir("wrong-base"), None).is_err());
+  assert!(restore(&[&incremental, &full], &backup_dir("reversed"), None).is_err());
+
+  restore(&[&full, &incremental], &backup_dir("restored"), None).unwrap();
+  assert_eq!(names(&open(&backup_dir("restored"))).await, ["a", "b", "c", "d", "e"]);
+
+  // A damaged chunk stops the restore before anything is copied.
+  std::fs::write(format!("{}/chunks/1.chk", full), b"damaged").unwrap();
+  assert!(restore(&[&full, &incremental], &backup_dir("damaged"), None).is_err());
+  assert!(!std::path::Path::new(&format!("{}/chunks", backup_dir("damaged"))).exists());
+
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn point_in_time_restores_keep_whole_appends() {
+  let dir = temp_dir("backup-point-in-time");
+  let data_dir = format!("{}/data", dir);
+  let store = open(&data_dir);
+
+  // a and b fill the first chunk, so c's append starts the second at a chunk boundary.  The
+  // transaction of d and e doesn't fit in the second chunk, e and its commit go in the third.
+  store.append("orders", vec![large("a")], None).await.unwrap();
+  store.append("orders", vec![large("b")], None).await.unwrap();
+  store.append("orders", vec![large("c")], None).await.unwrap();
+  store.append_to_streams(vec![
+    ("orders".to_string(), vec![large("d")]),
+    ("users".to_string(), vec![large("e")])
+  ]).await.unwrap();
+  store.append("orders", vec![ProposedEvent::new("Added", "f")], None).await.unwrap();
+  let ids : Vec<u64> = read_all(&store, "$all").await.iter().map(|event| event.id).collect();
+  drop(store);
+
+  let restored = |name: &str, until: u64| {
+    let data_dir = format!("{}/{}", dir, name);
+    let summary  = restore(&[&format!("{}/data", dir)], &data_dir, Some(RestorePoint::EventId(until))).unwrap();
+    (summary, data_dir)
+  };
+
+  let (summary, at_boundary) = restored("at-boundary", ids[1]);
+  assert_eq!(summary.chunks, 1);
+  assert_eq!(names(&open(&at_boundary)).await, ["a", "b"]);
+
+  // Only part of the transaction is before the point, so none of it is restored.
+  let (summary, within_transaction) = restored("within-transaction", ids[3]);
+  assert_eq!((summary.chunks, summary.next_id), (2, ids[3]));
+  let store = open(&within_transaction);
+  assert_eq!(names(&store).await, ["a", "b", "c"]);
+  assert!(store.read("users", 0).is_err());
+
+  let (summary, after_transaction) = restored("after-transaction", ids[4]);
+  assert_eq!(summary.chunks, 3);
+  assert_eq!(names(&open(&after_transaction)).await, ["a", "b", "c", "d", "e"]);
+
+  std::fs::remove_dir_all(&dir).unwrap();
+}
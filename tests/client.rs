@@ -0,0 +1,34 @@
+// Runs the server binary in a directory of its own and talks to it through the client.
+
+mod common;
+
+use std::time::Duration;
+
+use betterstore::client::EventData;
+use common::Server;
+use futures::StreamExt;
+
+#[tokio::test]
+async fn subscription_to_all_resumes_after_the_server_restarts() {
+  let mut server = Server::start();
+  let client = server.client().await;
+
+  client.append("a", vec![EventData::new("Added", "a0")], None).await.unwrap();
+  client.append("b", vec![EventData::new("Added", "b0")], None).await.unwrap();
+  client.append("a", vec![EventData::new("Added", "a1")], None).await.unwrap();
+
+  let mut all = client.subscribe("$all", 0);
+  for (revision, payload) in [(0, "a0"), (1, "b0")] {
+    let event = all.next().await.unwrap().unwrap();
+    assert_eq!((event.revision, event.payload.as_str()), (revision, payload));
+  }
+
+  server.restart();
+  server.client().await.append("c", vec![EventData::new("Added", "c0")], None).await.unwrap();
+
+  // Carries on after b0, the last event taken, with neither a repeat nor a gap.
+  for (revision, payload) in [(2, "a1"), (3, "c0")] {
+    let event = tokio::time::timeout(Duration::from_secs(30), all.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!((event.revision, event.payload.as_str()), (revision, payload));
+  }
+}
@@ -0,0 +1,57 @@
+// The server binary run in a directory of its own, for tests that talk to it over the network.
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use betterstore::client::Client;
+
+pub struct Server {
+  pub dir     : PathBuf,
+  pub address : String,
+  process     : Option<Child>
+}
+
+impl Server {
+  pub fn start() -> Self {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let dir = std::env::temp_dir().join(format!("betterstore-server-{}-{}", std::process::id(), nanos));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Whatever port is free now, the server binds it a moment later.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Self { dir, address: format!("127.0.0.1:{}", port), process: None };
+    server.restart();
+    server
+  }
+
+  pub fn restart(&mut self) {
+    self.stop();
+    let process = Command::new(env!("CARGO_BIN_EXE_server"))
+      .current_dir(&self.dir)
+      .env("BETTERSTORE_LISTEN", &self.address)
+      .stdout(Stdio::null())
+      .spawn()
+      .unwrap();
+    self.process = Some(process);
+  }
+
+  pub fn stop(&mut self) {
+    if let Some(mut process) = self.process.take() {
+      process.kill().unwrap();
+      process.wait().unwrap();
+    }
+  }
+
+  pub async fn client(&self) -> Client {
+    Client::connect(&format!("betterstore://{}?max_retries=20&retry_delay_ms=50", self.address)).await.unwrap()
+  }
+}
+
+impl Drop for Server {
+  fn drop(&mut self) {
+    self.stop();
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
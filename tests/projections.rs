@@ -0,0 +1,106 @@
+// Projections run against a store kept in memory.  They write through append_system_streams,
+// which blocks, so they run on the test's own thread.
+
+use betterstore::actor::projection::Projections;
+use betterstore::store::{Store, ProposedEvent, ReadError};
+use futures::executor::block_on;
+
+fn event(event_type: &str, payload: &str) -> ProposedEvent {
+  ProposedEvent::new(event_type, payload)
+}
+
+#[test]
+fn projections_do_not_handle_the_events_they_emit() {
+  let store = Store::in_memory();
+  let script = r#"
+    fn init() { #{ n: 0 } }
+    fn handle(state, event) { state.n += 1; emit("seen", "Seen", event.data); state }
+  "#;
+
+  let mut projections = Projections::load(store.engine());
+  projections.create(store.engine(), "seen", "$all", script).unwrap();
+  for payload in ["a0", "a1", "a2"] {
+    block_on(store.append("a", vec![event("Added", payload)], None)).unwrap();
+  }
+  for _ in 0 .. 5 {
+    projections.run(store.engine());
+  }
+
+  let status = projections.status("seen").unwrap();
+  assert_eq!((status.status.as_str(), status.state.as_str()), ("Running", r#"{"n":3}"#));
+  assert_eq!(store.stream_info("seen").unwrap().last_revision, 2);
+
+  // Loaded again, it carries on from its checkpoint and still skips its own events.
+  let mut projections = Projections::load(store.engine());
+  block_on(store.append("a", vec![event("Added", "a3")], None)).unwrap();
+  for _ in 0 .. 5 {
+    projections.run(store.engine());
+  }
+  assert_eq!(projections.status("seen").unwrap().state, r#"{"n":4}"#);
+  assert_eq!(store.stream_info("seen").unwrap().last_revision, 3);
+}
+
+#[test]
+fn projections_fault_when_their_checkpoint_cannot_be_written() {
+  let store = Store::in_memory();
+  let script = r#"
+    fn init() { #{ data: "" } }
+    fn handle(state, event) { state.data += event.data; state }
+  "#;
+
+  let mut projections = Projections::load(store.engine());
+  projections.create(store.engine(), "big", "a", script).unwrap();
+  let payload = "x".repeat(300_000);
+  for _ in 0 .. 2 {
+    block_on(store.append("a", vec![event("Added", &payload)], None)).unwrap();
+  }
+  projections.run(store.engine());
+
+  // Nothing of the batch is kept, it's handled again once started.
+  let status = projections.status("big").unwrap();
+  assert_eq!((status.status.as_str(), status.position, status.state.as_str()), ("Faulted", 0, r#"{"data":""}"#));
+  assert!(status.error.contains("checkpoint"));
+}
+
+#[test]
+fn projections_fault_when_emitting_to_system_streams() {
+  let store = Store::in_memory();
+  let script = r#"fn handle(state, event) { emit("$nope", "Seen", event.data); state }"#;
+
+  let mut projections = Projections::load(store.engine());
+  projections.create(store.engine(), "nope", "a", script).unwrap();
+  block_on(store.append("a", vec![event("Added", "a0")], None)).unwrap();
+  projections.run(store.engine());
+
+  assert_eq!(projections.status("nope").unwrap().status, "Faulted");
+  assert!(matches!(store.stream_info("$nope"), Err(ReadError::StreamNotFound(_))));
+}
+
+#[test]
+fn projections_that_cannot_be_loaded_fault_instead_of_stopping_the_load() {
+  let store  = Store::in_memory();
+  let engine = store.engine();
+  let script = r#"fn handle(state, event) { state }"#;
+
+  let mut projections = Projections::load(engine);
+  projections.create(engine, "kept", "a", script).unwrap();
+  projections.create(engine, "checkpointed", "a", script).unwrap();
+  projections.reset(engine, "checkpointed").unwrap();
+  projections.start(engine, "checkpointed").unwrap();
+
+  let system = |stream_name: &str, event_type: &str, payload: &str| {
+    engine.append_system_events(stream_name.to_string(), vec![event(event_type, payload)]).unwrap();
+  };
+  system("$projections-checkpointed-checkpoint", "$ProjectionCheckpoint", "not a checkpoint");
+  system("$projections", "$ProjectionCreated", r#"{"name": "broken"}"#);
+  system("$projections", "$ProjectionStarted", "not a control event");
+  system("$projections", "$ProjectionStarted", r#"{"name": "never-created", "error": null}"#);
+
+  let mut projections = Projections::load(engine);
+  assert_eq!(projections.status("kept").unwrap().status, "Running");
+  for name in ["checkpointed", "broken"] {
+    assert_eq!(projections.status(name).unwrap().status, "Faulted");
+    assert!(projections.start(engine, name).is_err());
+  }
+  assert!(projections.status("checkpointed").unwrap().error.contains("checkpoint"));
+}
@@ -0,0 +1,333 @@
+// The store as a library, kept in memory.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use betterstore::actor::engine::{ChunkStorage, MemoryStorage};
+use betterstore::store::{AppendError, Store, EngineOptions, ProposedEvent, ReadError, ReadOptions, RecordedEvent};
+use futures::StreamExt;
+
+fn with_storage(storage: &Arc<MemoryStorage>) -> Store {
+  Store::open(EngineOptions { storage: Some(storage.clone()), ..EngineOptions::default() })
+}
+
+fn temp_dir(name: &str) -> String {
+  let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+  let dir = std::env::temp_dir().join(format!("betterstore-{}-{}-{}", name, std::process::id(), nanos));
+  std::fs::create_dir_all(&dir).unwrap();
+  dir.to_str().unwrap().to_string()
+}
+
+fn event(event_type: &str, payload: &str) -> ProposedEvent {
+  ProposedEvent::new(event_type, payload)
+}
+
+fn with_id(payload: &str, event_id: &str) -> ProposedEvent {
+  ProposedEvent { event_id: Some(event_id.to_string()), ..event("Added", payload) }
+}
+
+async fn read_all(store: &Store, stream_name: &str) -> Vec<RecordedEvent> {
+  store.read(stream_name, 0).unwrap().map(|event| event.unwrap()).collect().await
+}
+
+fn payloads(events: &[RecordedEvent]) -> Vec<&str> {
+  events.iter().map(|event| event.payload.as_str()).collect()
+}
+
+#[tokio::test]
+async fn appended_events_are_read_back_in_order() {
+  let store = Store::in_memory();
+
+  assert_eq!(store.append("orders", vec![event("Placed", "1"), event("Paid", "2")], Some(-1)).await, Ok(1));
+  assert_eq!(store.append("orders", vec![event("Shipped", "3")], Some(1)).await, Ok(2));
+  assert!(matches!(store.append("orders", vec![event("Shipped", "4")], Some(1)).await, Err(AppendError::WrongExpectedVersion(_))));
+
+  let events = read_all(&store, "orders").await;
+  assert_eq!(payloads(&events), ["1", "2", "3"]);
+  assert_eq!(events.iter().map(|event| event.revision).collect::<Vec<_>>(), [0, 1, 2]);
+  assert_eq!(events[2].event_type, "Shipped");
+
+  assert!(matches!(store.read("missing", 0), Err(ReadError::StreamNotFound(_))));
+}
+
+#[tokio::test]
+async fn reopening_the_storage_rebuilds_the_index() {
+  let storage = Arc::new(MemoryStorage::new());
+
+  let store = with_storage(&storage);
+  store.append("orders-1", vec![event("Placed", "a")], None).await.unwrap();
+  store.append("orders-2", vec![with_id("b", "order-2")], None).await.unwrap();
+  store.append("orders-1", vec![event("Paid", "c")], None).await.unwrap();
+  let last_id = read_all(&store, "orders-1").await[1].id;
+  drop(store);
+
+  let store = with_storage(&storage);
+  assert_eq!(payloads(&read_all(&store, "orders-1").await), ["a", "c"]);
+  assert_eq!(payloads(&read_all(&store, "$ce-orders").await), ["a", "b", "c"]);
+  assert_eq!(store.stream_info("orders-2").unwrap().last_revision, 0);
+
+  // Ids carry on from the last event, and event ids are still known.
+  store.append("orders-2", vec![event("Paid", "d")], Some(0)).await.unwrap();
+  assert!(read_all(&store, "orders-2").await[1].id > last_id);
+  assert_eq!(store.append("orders-2", vec![with_id("b", "order-2")], None).await, Ok(1));
+}
+
+// A chunk as the first version of the server wrote them: a header of hash, version and timestamp,
+// then records of a length followed by an event without a type.
+fn v1_chunk(events: &[(u64, &str, &str)]) -> Vec<u8> {
+  let mut chunk = vec![0; 32];
+  chunk.push(1);
+  chunk.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+  for (id, stream_name, payload) in events {
+    let mut event = Vec::new();
+    event.extend_from_slice(&id.to_le_bytes());
+    event.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+    for field in [stream_name, payload] {
+      event.extend_from_slice(&(field.len() as u64).to_le_bytes());
+      event.extend_from_slice(field.as_bytes());
+    }
+    chunk.extend_from_slice(&(event.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(&event);
+  }
+
+  // The hash covers the chunk with the hash itself zero filled.
+  let hash = ring::digest::digest(&ring::digest::SHA256, &chunk);
+  chunk[.. 32].copy_from_slice(hash.as_ref());
+  chunk
+}
+
+#[tokio::test]
+async fn chunks_from_before_event_types_are_still_read() {
+  let storage = Arc::new(MemoryStorage::new());
+  storage.create(1).unwrap();
+  storage.append(1, &v1_chunk(&[(0, "orders-1", "o0"), (1, "users-1", "u0"), (2, "orders-1", "o1")])).unwrap();
+
+  let store = with_storage(&storage);
+  let events = read_all(&store, "orders-1").await;
+  assert_eq!(payloads(&events), ["o0", "o1"]);
+  assert_eq!(events[1].event_type, "");
+  assert_eq!(payloads(&read_all(&store, "$ce-orders").await), ["o0", "o1"]);
+
+  // New events go to a chunk of the current version after it.
+  store.append("orders-1", vec![event("Paid", "o2")], Some(1)).await.unwrap();
+  drop(store);
+
+  let store = with_storage(&storage);
+  assert_eq!(payloads(&read_all(&store, "$all").await), ["o0", "u0", "o1", "o2"]);
+  assert_eq!(payloads(&read_all(&store, "$et-Paid").await), ["o2"]);
+}
+
+#[tokio::test]
+async fn links_are_read_as_the_events_they_point_at() {
+  let store = Store::in_memory();
+  store.append("orders", vec![event("Placed", "o0"), event("Paid", "o1")], None).await.unwrap();
+  store.append("paid", vec![event("$>", "1@orders")], None).await.unwrap();
+  assert!(matches!(store.append("paid", vec![event("$>", "5@orders")], None).await, Err(AppendError::Rejected(_))));
+
+  let links = read_all(&store, "paid").await;
+  assert_eq!((links[0].event_type.as_str(), links[0].payload.as_str()), ("$>", "1@orders"));
+
+  let options  = ReadOptions { resolve_links: true, ..ReadOptions::default() };
+  let resolved : Vec<RecordedEvent> = store.read_with("paid", options).unwrap().map(|event| event.unwrap()).collect().await;
+  assert_eq!(resolved.len(), 1);
+  // The revision stays the link's own, in the stream read.
+  assert_eq!((resolved[0].stream_name.as_str(), resolved[0].revision, resolved[0].payload.as_str()), ("orders", 0, "o1"));
+  assert_eq!(resolved[0].link_id, Some(links[0].id));
+}
+
+#[tokio::test]
+async fn appending_to_several_streams_writes_all_of_them_or_none() {
+  let store = Store::in_memory();
+
+  let streams = vec![
+    ("a".to_string(), vec![event("Added", "a0")]),
+    ("b".to_string(), vec![event("Added", "b0"), event("Added", "b1")])
+  ];
+  store.append_to_streams(streams).await.unwrap();
+  assert_eq!(payloads(&read_all(&store, "$all").await), ["a0", "b0", "b1"]);
+
+  // The second stream is refused, so nothing of the first is written either.
+  let streams = vec![
+    ("a".to_string(), vec![event("Added", "a1")]),
+    ("$b".to_string(), vec![event("Added", "b2")])
+  ];
+  assert!(store.append_to_streams(streams).await.is_err());
+  assert_eq!(payloads(&read_all(&store, "a").await), ["a0"]);
+  assert_eq!(payloads(&read_all(&store, "$all").await), ["a0", "b0", "b1"]);
+
+  // Nor is a transaction carrying on after the failed one seen as part of it.
+  store.append("a", vec![event("Added", "a2")], Some(0)).await.unwrap();
+  assert_eq!(payloads(&read_all(&store, "a").await), ["a0", "a2"]);
+}
+
+#[tokio::test]
+async fn streams_are_listed_a_page_at_a_time_with_their_metadata() {
+  let storage = Arc::new(MemoryStorage::new());
+
+  let store = with_storage(&storage);
+  for stream_name in ["orders-2", "users-1", "orders-1", "orders-3"] {
+    store.append(stream_name, vec![event("Added", stream_name)], None).await.unwrap();
+  }
+  store.set_stream_metadata("orders-1", r#"{"owner": "sales"}"#).await.unwrap();
+  store.set_stream_metadata("orders-1", r#"{"owner": "billing"}"#).await.unwrap();
+  assert!(matches!(store.set_stream_metadata("orders-1", "owner").await, Err(AppendError::Rejected(_))));
+  store.delete_stream("orders-2").await.unwrap();
+  drop(store);
+
+  let store = with_storage(&storage);
+  let page = store.list_streams("orders-", "", 1);
+  assert_eq!((page.stream_names, page.next_page_token.as_str()), (vec!["orders-1".to_string()], "orders-1"));
+  // The deleted stream is left out.
+  let page = store.list_streams("orders-", "orders-1", 1);
+  assert_eq!((page.stream_names, page.next_page_token.as_str()), (vec!["orders-3".to_string()], ""));
+
+  let info = store.stream_info("orders-1").unwrap();
+  assert_eq!((info.metadata.as_str(), info.deleted), (r#"{"owner": "billing"}"#, false));
+
+  let info = store.stream_info("orders-2").unwrap();
+  assert_eq!((info.event_count, info.deleted), (1, true));
+  assert!(matches!(store.read("orders-2", 0), Err(ReadError::StreamNotFound(_))));
+  assert!(matches!(store.append("orders-2", vec![event("Added", "again")], None).await, Err(AppendError::Rejected(_))));
+  assert_eq!(payloads(&read_all(&store, "$ce-orders").await), ["orders-2", "orders-1", "orders-3"]);
+}
+
+#[tokio::test]
+async fn rotating_the_key_starts_a_chunk_encrypted_with_the_new_key() {
+  let dir      = temp_dir("keys");
+  let key_file = format!("{}/keys", dir);
+  let storage  = Arc::new(MemoryStorage::new());
+  let with_key = || Store::open(EngineOptions {
+    storage  : Some(storage.clone()),
+    key_file : Some(key_file.clone()),
+    ..EngineOptions::default()
+  });
+
+  std::fs::write(&key_file, format!("1 {}\n", "11".repeat(32))).unwrap();
+  let store = with_key();
+  store.append("orders", vec![event("Placed", "card 4111")], None).await.unwrap();
+  drop(store);
+  assert!(!String::from_utf8_lossy(&storage.read(1).unwrap()).contains("card 4111"));
+
+  std::fs::write(&key_file, format!("1 {}\n2 {}\n", "11".repeat(32), "22".repeat(32))).unwrap();
+  let store = with_key();
+  store.append("orders", vec![event("Paid", "card 5500")], None).await.unwrap();
+  assert_eq!(storage.list().unwrap(), [1, 2]);
+  assert_eq!(payloads(&read_all(&store, "orders").await), ["card 4111", "card 5500"]);
+  assert!(!String::from_utf8_lossy(&storage.read(2).unwrap()).contains("card 5500"));
+
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn shredded_streams_stay_unreadable_after_a_restart() {
+  let stream_keys_dir = temp_dir("stream-keys");
+  let storage = Arc::new(MemoryStorage::new());
+  let with_keys = || Store::open(EngineOptions {
+    storage         : Some(storage.clone()),
+    stream_keys_dir : Some(stream_keys_dir.clone()),
+    ..EngineOptions::default()
+  });
+
+  let store = with_keys();
+  store.append("user-1", vec![event("Registered", "alice@example.com")], None).await.unwrap();
+  store.append("user-2", vec![event("Registered", "bob@example.com")], None).await.unwrap();
+  assert!(!store.stream_info("user-1").unwrap().shredded);
+  assert!(!String::from_utf8_lossy(&storage.read(1).unwrap()).contains("alice@example.com"));
+
+  store.shred_stream("user-1").unwrap();
+  assert!(store.stream_info("user-1").unwrap().shredded);
+  assert_eq!(payloads(&read_all(&store, "user-1").await), [""]);
+  assert!(store.append("user-1", vec![event("Renamed", "more")], None).await.is_err());
+  assert!(store.append_to_streams(vec![("user-1".to_string(), vec![event("Renamed", "more")])]).await.is_err());
+  drop(store);
+
+  let store = with_keys();
+  assert!(store.stream_info("user-1").unwrap().shredded);
+  assert_eq!(payloads(&read_all(&store, "$all").await), ["", "bob@example.com"]);
+  assert!(store.append("user-1", vec![event("Renamed", "more")], None).await.is_err());
+
+  std::fs::remove_dir_all(&stream_keys_dir).unwrap();
+}
+
+#[tokio::test]
+async fn chunks_that_cannot_be_read_end_reads_with_unavailable() {
+  let storage = Arc::new(MemoryStorage::new());
+  let store   = with_storage(&storage);
+
+  // Enough to fill the first chunk and start a second.
+  let payload = "x".repeat(100_000);
+  for _ in 0 .. 12 {
+    store.append("a", vec![event("Added", &payload)], None).await.unwrap();
+  }
+  storage.remove(1).unwrap();
+
+  let mut read = store.read("a", 0).unwrap();
+  assert!(matches!(read.next().await, Some(Err(ReadError::Unavailable(_)))));
+  assert!(read.next().await.is_none());
+
+  let mut subscription = Box::pin(store.subscribe("a", 0));
+  assert!(matches!(subscription.next().await, Some(Err(ReadError::Unavailable(_)))));
+  assert!(subscription.next().await.is_none());
+
+  assert!(matches!(store.event_proof("a", 0).await, Err(ReadError::Unavailable(_))));
+}
+
+#[tokio::test]
+async fn subscriptions_number_events_by_where_they_are_in_the_stream_read() {
+  let store = Store::in_memory();
+  store.append("a", vec![event("Added", "a0")], None).await.unwrap();
+  store.append("b", vec![event("Added", "b0")], None).await.unwrap();
+
+  let mut all = Box::pin(store.subscribe("$all", 1));
+  store.append("a", vec![event("Added", "a1")], None).await.unwrap();
+
+  let events : Vec<RecordedEvent> = all.by_ref().take(2).map(|event| event.unwrap()).collect().await;
+  let seen : Vec<(u64, &str, &str)> = events.iter()
+    .map(|event| (event.revision, event.stream_name.as_str(), event.payload.as_str()))
+    .collect();
+  assert_eq!(seen, [(1, "b", "b0"), (2, "a", "a1")]);
+
+  // A stream that doesn't exist yet is waited for.
+  let mut later = Box::pin(store.subscribe("later", 0));
+  store.append("later", vec![event("Added", "l0")], None).await.unwrap();
+  assert_eq!(later.next().await.unwrap().unwrap().payload, "l0");
+}
+
+#[tokio::test]
+async fn only_the_latest_event_ids_are_remembered() {
+  let store = Store::open(EngineOptions { storage: Some(Arc::new(MemoryStorage::new())), event_id_window: 2, ..EngineOptions::default() });
+
+  store.append("a", vec![with_id("a0", "first")], None).await.unwrap();
+  store.append("a", vec![with_id("a1", "second"), with_id("a2", "third")], None).await.unwrap();
+  assert_eq!(store.append("a", vec![with_id("a2", "third")], None).await, Ok(2));
+
+  // The first id has made way for the later ones, so the append is written again.
+  assert_eq!(store.append("a", vec![with_id("a0", "first")], None).await, Ok(3));
+  assert_eq!(payloads(&read_all(&store, "a").await), ["a0", "a1", "a2", "a0"]);
+}
+
+#[tokio::test]
+async fn retried_appends_are_only_written_once() {
+  let store = Store::in_memory();
+
+  let append = || vec![with_id("a0", "first"), with_id("a1", "second")];
+  assert_eq!(store.append("a", append(), None).await, Ok(1));
+  assert_eq!(store.append("a", append(), None).await, Ok(1));
+  assert_eq!(payloads(&read_all(&store, "a").await), ["a0", "a1"]);
+
+  // Reusing only some of the ids is another append, not a retry.
+  assert!(store.append("a", vec![with_id("a2", "second"), with_id("a3", "third")], None).await.is_err());
+  assert!(store.append("a", vec![with_id("a2", "twice"), with_id("a3", "twice")], None).await.is_err());
+}
+
+#[tokio::test]
+async fn an_append_whose_ids_do_not_fit_in_a_chunk_is_refused() {
+  let store = Store::in_memory();
+
+  let events = (0 .. 30_000).map(|i| with_id("", &format!("event-{:032}", i))).collect();
+  assert!(store.append("a", events, None).await.is_err());
+
+  // The writer carries on as if it was never asked.
+  assert_eq!(store.append("a", vec![event("Added", "a0")], Some(-1)).await, Ok(0));
+  assert_eq!(payloads(&read_all(&store, "$all").await), ["a0"]);
+}
@@ -0,0 +1,77 @@
+// Exports and imports with the admin tool, between servers run as in tests/client.rs.
+
+mod common;
+
+use std::process::Command;
+
+use betterstore::client::{Client, EventData};
+use common::Server;
+use futures::StreamExt;
+
+fn admin(args: &[&str], server: &Server) -> Result<(), String> {
+  let output = Command::new(env!("CARGO_BIN_EXE_betterstore-admin"))
+    .args(args)
+    .args(["--server", &format!("http://{}", server.address)])
+    .output()
+    .unwrap();
+  match output.status.success() {
+    true  => Ok(()),
+    false => Err(String::from_utf8_lossy(&output.stderr).to_string())
+  }
+}
+
+#[tokio::test]
+async fn exports_import_into_a_fresh_store_as_they_were() {
+  let source = Server::start();
+  let client = source.client().await;
+  client.append("orders-1", vec![
+    EventData::new("Placed", r#"{"total": 5}"#).with_metadata(r#"{"user": "u1"}"#),
+    EventData::new("Paid", "{}")
+  ], None).await.unwrap();
+  client.append("users-1", vec![EventData::new("Registered", "alice").with_metadata("not json")], None).await.unwrap();
+  client.append("orders-1", vec![EventData::new("$>", "0@users-1")], None).await.unwrap();
+
+  let exported = source.dir.join("exported.jsonl");
+  admin(&["export", "--output", exported.to_str().unwrap()], &source).unwrap();
+
+  let target = Server::start();
+  let target_client = target.client().await;
+  admin(&["import", exported.to_str().unwrap(), "--keep-timestamps"], &target).unwrap();
+
+  let reexported = target.dir.join("reexported.jsonl");
+  admin(&["export", "--output", reexported.to_str().unwrap()], &target).unwrap();
+  assert_eq!(std::fs::read_to_string(&reexported).unwrap(), std::fs::read_to_string(&exported).unwrap());
+
+  let events : Vec<_> = target_client.read("orders-1", 0).map(|event| event.unwrap()).collect().await;
+  let read_back : Vec<_> = events.iter().map(|event| (event.event_type.as_str(), event.payload.as_str(), event.metadata.as_str())).collect();
+  assert_eq!(read_back, [("Placed", r#"{"total": 5}"#, r#"{"user":"u1"}"#), ("Paid", "{}", ""), ("$>", "0@users-1", "")]);
+}
+
+async fn read_back(client: &Client, stream_name: &str) -> Vec<(String, String, String)> {
+  client.read(stream_name, 0)
+    .map(|event| event.unwrap())
+    .map(|event| (event.event_type, event.payload, event.metadata))
+    .collect().await
+}
+
+#[tokio::test]
+async fn esdb_dumps_import_with_their_metadata() {
+  let dump = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/esdb.json");
+
+  // The dump goes stream by stream, customer-1 was created before order-1 was shipped.
+  let keeping = Server::start();
+  keeping.client().await;
+  let error = admin(&["import", dump, "--format", "esdb", "--keep-timestamps"], &keeping).unwrap_err();
+  assert!(error.contains("Timestamp"), "{}", error);
+
+  let server = Server::start();
+  let client = server.client().await;
+  admin(&["import", dump, "--format", "esdb"], &server).unwrap();
+
+  let owned = |event: (&str, &str, &str)| (event.0.to_string(), event.1.to_string(), event.2.to_string());
+  assert_eq!(read_back(&client, "order-1").await, [
+    owned(("Placed", r#"{"total":5}"#, r#"{"$correlationId":"c1"}"#)),
+    owned(("Shipped", "{}", ""))
+  ]);
+  assert_eq!(read_back(&client, "customer-1").await, [owned(("Registered", "alice", "imported"))]);
+}